@@ -1,38 +1,130 @@
-use crate::state;
+use crate::{
+    command::{ClientHello, Encoding, Event, ServerCommand, PROTOCOL_VERSION},
+    metrics, state,
+};
 use futures::{SinkExt, StreamExt, TryStreamExt};
-use warp::reply::{Reply, Response};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::time::interval;
+use tracing::Instrument;
+use warp::{
+    filters::ws::Message,
+    http::StatusCode,
+    hyper::body::Bytes,
+    reply::{Reply, Response},
+};
 
-pub static mut URL_BASE: String = String::new();
-pub static mut QRCODE_URL_PREFIX: String = String::new();
+pub static URL_BASE: OnceLock<String> = OnceLock::new();
+pub static QRCODE_URL_PREFIX: OnceLock<String> = OnceLock::new();
+/// tokens accepted by the REST api, the api is disabled if there are none
+pub static API_TOKENS: OnceLock<Vec<String>> = OnceLock::new();
+/// tokens accepted by the admin console, it is disabled if there are none
+pub static ADMIN_TOKENS: OnceLock<Vec<String>> = OnceLock::new();
+/// when the server started, for the uptime in the admin console
+pub static STARTED: OnceLock<Instant> = OnceLock::new();
+
+fn url_base() -> &'static str {
+    URL_BASE.get().map(String::as_str).unwrap_or("/")
+}
 
 fn set_cookie(room: &str, sckid: u32) -> String {
-    let base = unsafe { URL_BASE.as_str() };
+    let base = url_base();
     format!("session={room}:{sckid}; max-age=3600; path={base};")
 }
 fn unset_cookie() -> String {
-    let base = unsafe { URL_BASE.as_str() };
+    let base = url_base();
     format!("session=; max-age=0; path={base};")
 }
+fn device_cookie(device: &str) -> String {
+    let base = url_base();
+    format!("device={device}; max-age=31536000; path={base};")
+}
+
+/// the device id identifies a browser across sessions, so that bans survive
+/// leaving and joining the room again
+fn device_or_random(device: Option<String>) -> (String, bool) {
+    match device {
+        Some(device) if !device.is_empty() && device.len() <= 32 => (device, false),
+        _ => {
+            use rand::Rng;
+            let mut rng = rand::thread_rng();
+            let device = (0..16)
+                .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+                .collect();
+            (device, true)
+        }
+    }
+}
+
+/// `with_header` replaces existing headers, this appends each cookie instead
+fn with_cookies(reply: impl Reply, cookies: &[String]) -> Response {
+    let mut response = reply.into_response();
+    for cookie in cookies {
+        if let Ok(value) = warp::http::HeaderValue::from_str(cookie) {
+            response
+                .headers_mut()
+                .append(warp::http::header::SET_COOKIE, value);
+        }
+    }
+    response
+}
 
-fn validate_roomid(roomid: &str) -> Result<(), Response> {
+fn validate_roomid(roomid: &str) -> bool {
     let bytes = roomid.as_bytes();
     fn is_vowel(byte: u8) -> bool {
         matches!(byte, b'A' | b'E' | b'I' | b'O' | b'U')
     }
     fn is_consonant(byte: u8) -> bool {
-        matches!(byte, b'A'..=b'Z') && !is_vowel(byte)
+        byte.is_ascii_uppercase() && !is_vowel(byte)
     }
 
     if let [a, b, c] = bytes {
-        if is_consonant(*a) && is_vowel(*b) && is_consonant(*c) {
-            return Ok(());
-        }
+        return is_consonant(*a) && is_vowel(*b) && is_consonant(*c);
     }
-    Err(warp::reply::with_status(
+    false
+}
+
+fn error_status(error: &state::Error) -> StatusCode {
+    use crate::command::ErrorCode;
+    match error.code() {
+        ErrorCode::RoomNotFound | ErrorCode::MemberNotFound => StatusCode::NOT_FOUND,
+        ErrorCode::Banned | ErrorCode::Kicked => StatusCode::FORBIDDEN,
+        ErrorCode::GameRunning => StatusCode::CONFLICT,
+        ErrorCode::NoFreeRoomCode => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+fn error_response(error: state::Error) -> Response {
+    let status = error_status(&error);
+    warp::reply::with_status(warp::reply::html(error.to_string()), status).into_response()
+}
+
+fn message_encoding(message: &Message) -> Encoding {
+    if message.is_binary() {
+        Encoding::MessagePack
+    } else {
+        Encoding::Json
+    }
+}
+
+/// the `cmd` field of a command that could not be handled, for the error reply
+fn command_name(message: &[u8], encoding: Encoding) -> Option<String> {
+    let value: serde_json::Value = state::decode(encoding, message).ok()?;
+    Some(value.get("cmd")?.as_str()?.to_owned())
+}
+
+fn bad_roomid() -> Response {
+    warp::reply::with_status(
         warp::reply::html("bad roomid"),
         warp::http::StatusCode::BAD_REQUEST,
     )
-    .into_response())
+    .into_response()
 }
 
 pub fn filter_get(reply: impl warp::Reply, session: Option<(String, u32)>) -> Response {
@@ -51,52 +143,49 @@ pub fn api_create() -> Response {
             warp::reply::with_header(warp::reply::html(room), "set-cookie", set_cookie)
                 .into_response()
         }
-        Err(()) => warp::reply::with_status(
-            warp::reply::html("Failed to create the room, presumably, it must be full"),
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        )
-        .into_response(),
+        Err(error) => error_response(error),
     }
 }
 
-pub fn api_join(room: String) -> Response {
-    if let Err(error) = validate_roomid(&room) {
-        return error;
+pub fn api_join(room: String, device: Option<String>) -> Response {
+    if !validate_roomid(&room) {
+        return bad_roomid();
     }
-    match state::join_room(&room) {
+    let (device, new_device) = device_or_random(device);
+    match state::join_room(&room, &device) {
         Ok(sckid) => {
-            let set_cookie = set_cookie(&room, sckid);
-            warp::reply::with_header(
+            let mut cookies = vec![set_cookie(&room, sckid)];
+            if new_device {
+                cookies.push(device_cookie(&device));
+            }
+            with_cookies(
                 warp::reply::with_header(
                     warp::reply::html(sckid.to_string()),
                     "content-type",
                     "text/plain",
                 ),
-                "set-cookie",
-                set_cookie,
+                &cookies,
             )
-            .into_response()
         }
-        Err(()) => warp::reply::with_status(
-            warp::reply::html("Room does not exist, or the game is running"),
-            warp::http::StatusCode::NOT_FOUND,
-        )
-        .into_response(),
+        Err(error) => error_response(error),
     }
 }
 
-pub fn api_join_redirect(room: String) -> Response {
-    let redirect =
-        warp::redirect::found(warp::http::Uri::from_static(unsafe { URL_BASE.as_str() }));
-    if let Err(_) = validate_roomid(&room) {
+pub fn api_join_redirect(room: String, device: Option<String>) -> Response {
+    let redirect = warp::redirect::found(warp::http::Uri::from_static(url_base()));
+    if !validate_roomid(&room) {
         return redirect.into_response();
     }
-    match state::join_room(&room) {
+    let (device, new_device) = device_or_random(device);
+    match state::join_room(&room, &device) {
         Ok(sckid) => {
-            let set_cookie = set_cookie(&room, sckid);
-            warp::reply::with_header(redirect, "set-cookie", set_cookie).into_response()
+            let mut cookies = vec![set_cookie(&room, sckid)];
+            if new_device {
+                cookies.push(device_cookie(&device));
+            }
+            with_cookies(redirect, &cookies)
         }
-        Err(()) => redirect.into_response(),
+        Err(_) => redirect.into_response(),
     }
 }
 
@@ -104,77 +193,548 @@ pub fn api_leave() -> Response {
     warp::reply::with_header(warp::reply::reply(), "set-cookie", unset_cookie()).into_response()
 }
 
+/// the outcome of the client's `Hello`
+struct Handshake {
+    /// the capabilities both sides support
+    capabilities: Vec<String>,
+    /// only set if the client asked for `resync`
+    last_seq: Option<u64>,
+}
+
+impl Handshake {
+    fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|x| x == capability)
+    }
+}
+
+/// waits for the client's `Hello`
+async fn handshake(
+    stream: &mut futures::stream::SplitStream<warp::ws::WebSocket>,
+) -> Result<Handshake, state::Error> {
+    const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+    let incompatible = state::Error::IncompatibleProtocol {
+        client_version: None,
+    };
+    let message = match tokio::time::timeout(HELLO_TIMEOUT, stream.next()).await {
+        Ok(Some(Ok(message))) => message,
+        _ => return Err(incompatible),
+    };
+    let hello = serde_json::from_slice(message.as_bytes()).map_err(|_| incompatible)?;
+    negotiate(hello)
+}
+
+/// checks the client's `Hello`, the same for every transport
+fn negotiate(hello: ClientHello) -> Result<Handshake, state::Error> {
+    use crate::command::{CAPABILITIES, MIN_PROTOCOL_VERSION};
+    let ClientHello::Hello {
+        protocol_version,
+        capabilities,
+        last_seq,
+    } = hello;
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol_version) {
+        return Err(state::Error::IncompatibleProtocol {
+            client_version: Some(protocol_version),
+        });
+    }
+    let mut handshake = Handshake {
+        capabilities: capabilities
+            .into_iter()
+            .filter(|x| CAPABILITIES.contains(&x.as_str()))
+            .collect(),
+        last_seq: None,
+    };
+    if handshake.has("resync") {
+        handshake.last_seq = last_seq;
+    }
+    Ok(handshake)
+}
+
 pub fn api_connect(ws: warp::ws::Ws, room: String, sckid: u32) -> Response {
-    if let Err(error) = validate_roomid(&room) {
-        return error;
-    }
-    #[cfg(debug_assertions)]
-    const DEBUG_WEB_SOCKET: bool = true;
-    #[cfg(not(debug_assertions))]
-    const DEBUG_WEB_SOCKET: bool = false;
-    match state::connect_room(&room, sckid) {
-        Ok(mut receiver) => ws
-            .on_upgrade(move |ws| async move {
-                if DEBUG_WEB_SOCKET {
-                    println!("[*] Websocket stream spawned");
+    if !validate_roomid(&room) {
+        return bad_roomid();
+    }
+    if let Err(error) = state::check_connect(&room, sckid) {
+        tracing::info!(room, sckid, %error, "could not connect to room");
+        return error_response(error);
+    }
+    let span = tracing::info_span!("connection", room, sckid);
+    ws.on_upgrade(move |ws| {
+        async move {
+            tracing::debug!("websocket opened");
+            let room = room;
+            let (mut sink, mut stream) = ws.split();
+            let connected = match handshake(&mut stream).await {
+                Ok(handshake) => {
+                    state::connect_room(&room, sckid, handshake.last_seq).map(|x| (handshake, x))
                 }
-                let room = room;
-                let _ = state::increment_online(&room, sckid);
-                let (mut sink, stream) = ws.split();
-                let sink_handler = async {
-                    while let Some(message) = receiver.recv().await {
-                        if sink.send(message).await.is_err() {
+                Err(error) => Err(error),
+            };
+            let (handshake, (reply, mut receiver)) = match connected {
+                Ok(connected) => connected,
+                Err(error) => {
+                    tracing::debug!(%error, "websocket handshake failed");
+                    let _ = sink
+                        .send(error.to_command(Some("Hello".to_owned())).into())
+                        .await;
+                    let _ = sink.close().await;
+                    return;
+                }
+            };
+            // the Hello itself is always json
+            let encoding = if handshake.has("msgpack") {
+                Encoding::MessagePack
+            } else {
+                Encoding::Json
+            };
+            let hello = ServerCommand::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: handshake.capabilities,
+            };
+            if sink.send(hello.into()).await.is_err() {
+                return;
+            }
+            let _ = state::increment_online(&room, sckid);
+            metrics::CONNECTIONS.with_label_values(&["websocket"]).inc();
+            let heartbeat = state::heartbeat();
+            // anything received counts, pongs included, a half-open connection receives nothing,
+            // in milliseconds since `opened`, an atomic because `on_upgrade` wants a `Send` future
+            let opened = Instant::now();
+            let last_received = AtomicU64::new(0);
+            let last_received = &last_received;
+            let since_received = move || {
+                let last_received = last_received.load(Ordering::Relaxed);
+                opened
+                    .elapsed()
+                    .saturating_sub(Duration::from_millis(last_received))
+            };
+            let timeout = Duration::from_secs(heartbeat.ping_timeout_secs);
+            let sink_handler = async {
+                let mut ping = interval(Duration::from_secs(heartbeat.ping_interval_secs.max(1)));
+                ping.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    let message = tokio::select! {
+                        event = receiver.recv() => match event {
+                            Some(event) => {
+                                metrics::MESSAGES_OUT
+                                    .with_label_values(&[event.command.name()])
+                                    .inc();
+                                event.to_message(encoding)
+                            }
+                            None => break,
+                        },
+                        _ = ping.tick() => {
+                            if since_received() > timeout {
+                                tracing::debug!("websocket ping timeout");
+                                break;
+                            }
+                            Message::ping(Vec::new())
+                        }
+                    };
+                    // a client that stopped reading fills the tcp buffers and the send
+                    // never completes, so the ping timeout above would never be checked
+                    match tokio::time::timeout(timeout, sink.send(message)).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(_)) => break,
+                        Err(_) => {
+                            tracing::debug!("websocket send timeout");
                             break;
                         }
                     }
-                };
-                let another_room = room.clone();
-                let mut stream_handler = stream
-                    .map(move |result| {
-                        println!("[*] Websocket message received! ok: {}", result.is_ok());
-                        result.map(|x| {
-                            if x.is_close() {
-                                return true;
-                            }
-                            if let Err(error) = state::handle_message(&another_room, sckid, x) {
-                                if DEBUG_WEB_SOCKET {
-                                    println!("[*] Websocket message handler error: {}", error.err);
+                }
+            };
+            let another_room = room.clone();
+            let mut stream_handler = stream
+                .map(move |result| {
+                    tracing::trace!(ok = result.is_ok(), "websocket message received");
+                    result.map(|x| {
+                        let elapsed = opened.elapsed().as_millis() as u64;
+                        last_received.store(elapsed, Ordering::Relaxed);
+                        if x.is_close() {
+                            return true;
+                        }
+                        if x.is_ping() || x.is_pong() {
+                            return false;
+                        }
+                        let reply_command =
+                            match state::handle_message(
+                                &another_room,
+                                sckid,
+                                x.as_bytes(),
+                                message_encoding(&x),
+                            ) {
+                                Ok(ack) => ack,
+                                Err(error) => {
+                                    tracing::debug!(%error, "command rejected");
+                                    Some(error.to_command(command_name(
+                                        x.as_bytes(),
+                                        message_encoding(&x),
+                                    )))
                                 }
-                            }
-                            false
-                        })
+                            };
+                        if let (Some(reply_command), Some(reply)) = (reply_command, reply.upgrade())
+                        {
+                            let _ = reply.try_send(Event::new(None, reply_command));
+                        }
+                        false
                     })
-                    .try_filter(|x| std::future::ready(*x))
-                    .map(|x| x.map(|_| ()));
-                let stream_handler = async { stream_handler.try_next().await.map(|_| ()) };
-                if let Err(error) =
-                    tokio::select!(() = sink_handler => Ok(()), b = stream_handler => b)
-                {
-                    println!("[*] Websocket error: {:?}", error);
-                }
-                let _ = sink.close().await;
-                let _ = state::decrement_online(&room, sckid);
-                if DEBUG_WEB_SOCKET {
-                    println!("[*] Websocket stream closed");
-                }
-            })
-            .into_response(),
-        Err(()) => {
-            println!("[!] Room/sckid does not exist");
-            warp::reply::with_status(
-                warp::reply::html("Room/sckid does not exist"),
-                warp::http::StatusCode::NOT_FOUND,
-            )
-            .into_response()
+                })
+                .try_filter(|x| std::future::ready(*x))
+                .map(|x| x.map(|_| ()));
+            let stream_handler = async { stream_handler.try_next().await.map(|_| ()) };
+            if let Err(error) = tokio::select!(() = sink_handler => Ok(()), b = stream_handler => b)
+            {
+                tracing::debug!(%error, "websocket error");
+            }
+            // closing sends a close frame, which may be stuck like the messages
+            let _ = tokio::time::timeout(timeout, sink.close()).await;
+            let _ = state::decrement_online(&room, sckid);
+            metrics::CONNECTIONS.with_label_values(&["websocket"]).dec();
+            tracing::debug!("websocket closed");
         }
-    }
+        .instrument(span)
+    })
+    .into_response()
 }
 
 pub fn api_qrcode(room: String) -> Response {
-    if let Err(error) = validate_roomid(&room) {
-        return error;
+    if !validate_roomid(&room) {
+        return bad_roomid();
     }
-    let url = unsafe { format!("{QRCODE_URL_PREFIX}{room}") };
+    let prefix = QRCODE_URL_PREFIX
+        .get()
+        .map(String::as_str)
+        .unwrap_or_default();
+    let url = format!("{prefix}{room}");
     let png = qrcode_generator::to_png_to_vec(url, qrcode_generator::QrCodeEcc::Low, 1024).unwrap();
     warp::reply::with_header(png, "content-type", "image/png").into_response()
 }
+
+/// the `Hello` of the server-sent events transport, sent in the query string
+#[derive(serde::Deserialize)]
+pub struct EventsQuery {
+    protocol_version: u32,
+    /// separated by commas
+    #[serde(default)]
+    capabilities: String,
+    #[serde(default)]
+    last_seq: Option<u64>,
+}
+
+/// marks the connection as offline when its stream is dropped
+struct Online {
+    room: String,
+    sckid: u32,
+}
+
+impl Drop for Online {
+    fn drop(&mut self) {
+        let _ = state::decrement_online(&self.room, self.sckid);
+        metrics::CONNECTIONS.with_label_values(&["sse"]).dec();
+    }
+}
+
+/// fallback for networks that block websockets, the server to client half of the
+/// connection as server-sent events, the commands are sent to `api_command`
+pub fn api_events(
+    room: String,
+    sckid: u32,
+    query: EventsQuery,
+    last_event_id: Option<u64>,
+) -> Response {
+    if !validate_roomid(&room) {
+        return bad_roomid();
+    }
+    let hello = ClientHello::Hello {
+        protocol_version: query.protocol_version,
+        capabilities: query
+            .capabilities
+            .split(',')
+            // server-sent events are text only
+            .filter(|x| !x.is_empty() && *x != "msgpack")
+            .map(str::to_owned)
+            .collect(),
+        // set by the browser when it reconnects on its own
+        last_seq: last_event_id.or(query.last_seq),
+    };
+    let connected = negotiate(hello).and_then(|handshake| {
+        state::connect_room(&room, sckid, handshake.last_seq)
+            .map(|(_, receiver)| (handshake, receiver))
+    });
+    let (handshake, receiver) = match connected {
+        Ok(connected) => connected,
+        Err(error) => return error_response(error),
+    };
+    let hello = Event::new(
+        None,
+        ServerCommand::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: handshake.capabilities,
+        },
+    );
+    let _ = state::increment_online(&room, sckid);
+    metrics::CONNECTIONS.with_label_values(&["sse"]).inc();
+    let online = Online { room, sckid };
+    let events = futures::stream::unfold((receiver, online), |(mut receiver, online)| async {
+        let event = receiver.recv().await?;
+        metrics::MESSAGES_OUT
+            .with_label_values(&[event.command.name()])
+            .inc();
+        Some((event.to_sse(), (receiver, online)))
+    });
+    let events = futures::stream::once(async move { hello.to_sse() })
+        .chain(events)
+        .map(Ok::<_, std::convert::Infallible>);
+    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+}
+
+/// the client to server half of the server-sent events transport, the body is the
+/// command, and the reply to the command, if any, is the body of the response
+pub fn api_command(room: String, sckid: u32, body: Bytes) -> Response {
+    if !validate_roomid(&room) {
+        return bad_roomid();
+    }
+    if let Err(error) = state::check_connect(&room, sckid) {
+        return error_response(error);
+    }
+    match state::handle_message(&room, sckid, &body, Encoding::Json) {
+        Ok(Some(reply)) => warp::reply::json(&reply).into_response(),
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => warp::reply::with_status(
+            warp::reply::json(&error.to_command(command_name(&body, Encoding::Json))),
+            error_status(&error),
+        )
+        .into_response(),
+    }
+}
+
+/// checks the `Authorization: Bearer <token>` header of a REST api request
+fn authorized(authorization: Option<String>) -> bool {
+    bearer_in(&API_TOKENS, authorization)
+}
+
+/// checks the `Authorization: Bearer <token>` header of an admin console request
+fn admin_authorized(authorization: Option<String>) -> bool {
+    bearer_in(&ADMIN_TOKENS, authorization)
+}
+
+/// the tokens are compared by their SHA-256 digests in constant time, so that neither
+/// the time taken nor the length of the token tells how much of it is right
+fn bearer_in(tokens: &OnceLock<Vec<String>>, authorization: Option<String>) -> bool {
+    use sha2::{Digest, Sha256};
+    use subtle::ConstantTimeEq;
+    let tokens = tokens.get().map(Vec::as_slice).unwrap_or_default();
+    let Some(token) = authorization
+        .as_deref()
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(str::trim)
+    else {
+        return false;
+    };
+    let token = Sha256::digest(token);
+    // every token is compared, not only until the first match
+    tokens
+        .iter()
+        .fold(subtle::Choice::from(0), |found, x| {
+            found | Sha256::digest(x).ct_eq(&token)
+        })
+        .into()
+}
+
+/// the REST api replies to errors with the same `Error` sent over the websocket
+fn rest_error(error: state::Error) -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&error.to_command(None)),
+        error_status(&error),
+    )
+    .into_response()
+}
+
+fn rest_reply<T: serde::Serialize>(result: Result<T, state::Error>) -> Response {
+    match result {
+        Ok(value) => warp::reply::json(&value).into_response(),
+        Err(error) => rest_error(error),
+    }
+}
+
+pub fn api_room(room: String, authorization: Option<String>) -> Response {
+    if !authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    rest_reply(state::room_info(&room))
+}
+
+pub fn api_room_members(room: String, authorization: Option<String>) -> Response {
+    if !authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    rest_reply(state::room_members(&room))
+}
+
+pub fn api_room_answers(room: String, authorization: Option<String>) -> Response {
+    if !authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    rest_reply(state::room_answers(&room))
+}
+
+/// the fields are applied in order, and the ones before an error stay applied
+pub fn api_room_update(
+    room: String,
+    authorization: Option<String>,
+    update: crate::command::RoomUpdate,
+) -> Response {
+    if !authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match state::master_commands(&room, update.into_commands()) {
+        Ok(()) => rest_reply(state::room_info(&room)),
+        Err(error) => rest_error(error),
+    }
+}
+
+/// start, finish or close the room, as if the master had done it
+pub fn api_room_command(
+    room: String,
+    authorization: Option<String>,
+    command: crate::command::MasterCommand,
+) -> Response {
+    if !authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match state::master_commands(&room, [command]) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(error) => rest_error(error),
+    }
+}
+
+/// the delivery log of the webhooks
+pub fn api_webhooks(authorization: Option<String>) -> Response {
+    if !authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    warp::reply::json(&crate::webhook::deliveries()).into_response()
+}
+
+/// the admin console page, the token is asked for by the page itself
+pub fn api_admin_page() -> Response {
+    match format!("{}admin.html", url_base()).parse::<warp::http::Uri>() {
+        Ok(uri) => warp::redirect::found(uri).into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// every room of the server
+pub fn api_admin_rooms(authorization: Option<String>) -> Response {
+    if !admin_authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    warp::reply::json(&state::admin_rooms()).into_response()
+}
+
+/// closes a room, even one whose master is gone
+pub fn api_admin_close(room: String, authorization: Option<String>) -> Response {
+    if !admin_authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    match state::master_commands(&room, [crate::command::MasterCommand::CloseRoom]) {
+        Ok(()) => {
+            tracing::info!(room, "room closed by the admin");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(error) => rest_error(error),
+    }
+}
+
+/// sends an `Announcement` to every room
+pub fn api_admin_announce(
+    authorization: Option<String>,
+    announcement: crate::command::AnnouncementRequest,
+) -> Response {
+    if !admin_authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let message = announcement.message.trim();
+    if message.is_empty() {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+    state::announce(message.to_owned());
+    StatusCode::NO_CONTENT.into_response()
+}
+
+pub fn api_admin_status(authorization: Option<String>) -> Response {
+    if !admin_authorized(authorization) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let (rooms, members_online) = state::room_counts();
+    let uptime = STARTED.get().map(Instant::elapsed).unwrap_or_default();
+    warp::reply::json(&crate::command::ServerStatus {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        uptime: uptime.as_secs() as u32,
+        rooms,
+        members_online,
+    })
+    .into_response()
+}
+
+/// LTI third party initiated login, redirects to the platform's authentication
+pub fn api_lti_login(request: crate::lti::LoginRequest) -> Response {
+    match crate::lti::login(request) {
+        Ok(url) => match url.parse::<warp::http::Uri>() {
+            Ok(uri) => warp::redirect::found(uri).into_response(),
+            Err(_) => StatusCode::BAD_REQUEST.into_response(),
+        },
+        Err(error) => lti_error_response(error),
+    }
+}
+
+/// LTI launch, puts the user in the room of the resource link and redirects to it
+pub async fn api_lti_launch(request: crate::lti::LaunchRequest) -> Response {
+    match crate::lti::launch(request).await {
+        Ok(launch) => {
+            let redirect = warp::redirect::found(warp::http::Uri::from_static(url_base()));
+            let set_cookie = set_cookie(&launch.room, launch.sckid);
+            warp::reply::with_header(redirect, "set-cookie", set_cookie).into_response()
+        }
+        Err(error) => lti_error_response(error),
+    }
+}
+
+fn lti_error_response(error: crate::lti::Error) -> Response {
+    use crate::lti::Error;
+    let status = match &error {
+        Error::Disabled | Error::NoRoom => StatusCode::NOT_FOUND,
+        Error::Keys(_) => StatusCode::BAD_GATEWAY,
+        Error::Room(error) => error_status(error),
+        _ => StatusCode::BAD_REQUEST,
+    };
+    warp::reply::with_status(warp::reply::html(error.to_string()), status).into_response()
+}
+
+pub fn api_metrics() -> Response {
+    warp::reply::with_header(
+        metrics::gather(),
+        "content-type",
+        "text/plain; version=0.0.4",
+    )
+    .into_response()
+}
+
+pub fn api_healthz() -> Response {
+    let health = crate::health::health();
+    let status = if health.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&health), status).into_response()
+}
+
+pub fn api_readyz() -> Response {
+    let readiness = crate::health::readiness();
+    let status = if readiness.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    warp::reply::with_status(warp::reply::json(&readiness), status).into_response()
+}
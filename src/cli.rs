@@ -0,0 +1,219 @@
+//! Command line options and `EXTENSAO_*` environment variables.
+//!
+//! Every option can also be set by an environment variable, named `EXTENSAO_`
+//! followed by the option in uppercase with underscores, like `EXTENSAO_TLS_CERT`
+//! for `--tls-cert`. Flags are set with `true`/`false` or `1`/`0`.
+//!
+//! Precedence, from highest to lowest: command line options, environment
+//! variables, the config file and the defaults of the config file.
+
+use std::{collections::BTreeMap, ffi::OsString, path::PathBuf};
+
+pub const HELP: &str = r"opções, também aceitas como variáveis de ambiente EXTENSAO_*:
+  --config <arquivo>  arquivo de configuração, extensao.json por padrão
+  --no-write          não criar o arquivo de configuração, usar os valores padrão
+  --bind <ip>         endereço IPv4, IPv6 ou hostname, todos se vazio
+  --port <porta>      porta
+  --tls, --no-tls     usar https ou não
+  --tls-cert <pem>    certificado do https
+  --tls-key <pem>     chave do certificado
+  --domain <domínio>  domínio usado nos qr codes
+  --base <caminho>    caminho base do site
+  --log-level <nível> nível dos logs, como info ou debug
+  --log-file <nome>   arquivo dos logs, a saída padrão se vazio
+--bind, --port e --tls substituem a lista listen do arquivo de configuração
+a linha de comando tem prioridade sobre as variáveis de ambiente, que têm
+prioridade sobre o arquivo de configuração";
+
+/// options that do not take a value on the command line
+const FLAGS: &[&str] = &["tls", "no-tls", "no-write"];
+/// options that can be set by an environment variable
+const VARIABLES: &[&str] = &[
+    "config",
+    "no-write",
+    "bind",
+    "port",
+    "tls",
+    "tls-cert",
+    "tls-key",
+    "domain",
+    "base",
+    "log-level",
+    "log-file",
+];
+
+#[derive(Default)]
+pub struct Options {
+    /// `extensao.json` next to the executable if not set
+    pub config: Option<PathBuf>,
+    /// never create the default config file, a missing one means the defaults
+    pub no_write: bool,
+    pub bind: Option<String>,
+    pub port: Option<u16>,
+    pub tls: Option<bool>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub domain: Option<String>,
+    pub base: Option<String>,
+    pub log_level: Option<String>,
+    pub log_file: Option<String>,
+    /// `EXTENSAO_*` environment variables that are not options, only warned about,
+    /// since something else in the environment may have set them
+    pub ignored: Vec<String>,
+    /// where each option that was set came from, like `--no-tls` or `EXTENSAO_PORT`,
+    /// by the name of the option
+    pub origins: BTreeMap<String, String>,
+}
+
+impl Options {
+    /// reads the environment variables, then the arguments on top of them
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        Self::parse_from(std::env::vars_os(), args)
+    }
+
+    /// the variables may be anything, only the `EXTENSAO_*` ones must be utf-8
+    fn parse_from(
+        variables: impl IntoIterator<Item = (OsString, OsString)>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self, String> {
+        let mut options = Self::default();
+        for (variable, value) in variables {
+            if !variable.as_encoded_bytes().starts_with(b"EXTENSAO_") {
+                continue;
+            }
+            let Some(variable) = variable.to_str() else {
+                // it can not be the name of an option
+                options
+                    .ignored
+                    .push(variable.to_string_lossy().into_owned());
+                continue;
+            };
+            let name = variable["EXTENSAO_".len()..]
+                .to_ascii_lowercase()
+                .replace('_', "-");
+            if !VARIABLES.contains(&name.as_str()) {
+                options.ignored.push(variable.to_owned());
+                continue;
+            }
+            let value = value.into_string().map_err(|value| {
+                format!("{variable}: valor não é UTF-8: {}", value.to_string_lossy())
+            })?;
+            options
+                .set(&name, value)
+                .map_err(|error| format!("{variable}: {error}"))?;
+            options.origins.insert(name, variable.to_owned());
+        }
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(option) = arg.strip_prefix("--") else {
+                return Err(format!("argumento inesperado: {arg}"));
+            };
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_owned())),
+                None => (option, None),
+            };
+            let value = match (FLAGS.contains(&name), value) {
+                (true, None) => match name {
+                    "no-tls" => "false".to_owned(),
+                    _ => "true".to_owned(),
+                },
+                (true, Some(_)) => return Err(format!("--{name} não aceita valor")),
+                (false, Some(value)) => value,
+                (false, None) => args
+                    .next()
+                    .ok_or_else(|| format!("--{name} precisa de um valor"))?,
+            };
+            let origin = format!("--{name}");
+            let name = if name == "no-tls" { "tls" } else { name };
+            options
+                .set(name, value)
+                .map_err(|error| format!("--{name}: {error}"))?;
+            options.origins.insert(name.to_owned(), origin);
+        }
+        Ok(options)
+    }
+
+    fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        match name {
+            "config" => self.config = Some(value.into()),
+            "no-write" => self.no_write = parse_bool(&value)?,
+            "bind" => self.bind = Some(value),
+            "port" => {
+                let port = value
+                    .parse()
+                    .map_err(|_| format!("porta inválida: {value}"))?;
+                self.port = Some(port);
+            }
+            "tls" => self.tls = Some(parse_bool(&value)?),
+            "tls-cert" => self.tls_cert = Some(value),
+            "tls-key" => self.tls_key = Some(value),
+            "domain" => self.domain = Some(value),
+            "base" => self.base = Some(value),
+            "log-level" => self.log_level = Some(value),
+            "log-file" => self.log_file = Some(value),
+            _ => return Err(format!("opção desconhecida: {name}")),
+        }
+        Ok(())
+    }
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(format!("esperado true ou false: {value}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn variables_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let variables = |list: &[(&[u8], &[u8])]| {
+            list.iter()
+                .map(|(name, value)| {
+                    (
+                        OsString::from_vec(name.to_vec()),
+                        OsString::from_vec(value.to_vec()),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let options = Options::parse_from(
+            variables(&[
+                (b"UNRELATED", b"\xff"),
+                (b"UNRELATED_\xff", b"1"),
+                (b"EXTENSAO_\xff", b"1"),
+                (b"EXTENSAO_FOO", b"\xff"),
+                (b"EXTENSAO_PORT", b"8080"),
+            ]),
+            [],
+        )
+        .unwrap();
+        assert_eq!(options.port, Some(8080));
+        assert_eq!(options.ignored, ["EXTENSAO_\u{fffd}", "EXTENSAO_FOO"]);
+
+        let error = Options::parse_from(variables(&[(b"EXTENSAO_BASE", b"/\xff/")]), [])
+            .err()
+            .unwrap();
+        assert_eq!(error, "EXTENSAO_BASE: valor não é UTF-8: /\u{fffd}/");
+    }
+
+    #[test]
+    fn arguments_override_variables() {
+        let options = Options::parse_from(
+            [("EXTENSAO_PORT", "8080"), ("EXTENSAO_TLS", "1")]
+                .map(|(name, value)| (name.into(), value.into())),
+            ["--port=9090".to_owned(), "--no-tls".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(options.port, Some(9090));
+        assert_eq!(options.tls, Some(false));
+        assert_eq!(options.origins["port"], "--port");
+        assert_eq!(options.origins["tls"], "--no-tls");
+    }
+}
@@ -1,4 +1,77 @@
-#[derive(serde::Serialize, serde::Deserialize)]
+//! The websocket protocol.
+//!
+//! Every websocket starts with the client sending `Hello`, the server replies
+//! with its own `Hello` holding the negotiated capabilities, or with an
+//! `Error` with the code `IncompatibleProtocol` before closing the socket.
+//!
+//! Compatibility policy, `PROTOCOL_VERSION` must be bumped for any change
+//! that is not listed as compatible below:
+//! - adding a variant to `ServerCommand` is compatible, clients must ignore
+//!   unknown values of `cmd`
+//! - adding a variant to `MasterCommand` or `MemberCommand` is compatible,
+//!   older clients simply never send it
+//! - adding a field to a server to client message is compatible, clients
+//!   must ignore unknown fields
+//! - adding a field to a client to server message is compatible only if it
+//!   is `#[serde(default)]`
+//! - optional behaviour is added as a new capability, and only enabled for
+//!   connections that asked for it in their `Hello`
+//! - anything else (removing or renaming variants or fields, changing types)
+//!   bumps `PROTOCOL_VERSION`, and `MIN_PROTOCOL_VERSION` is raised once the
+//!   server no longer understands the previous version
+//!
+//! Messages are json text by default. With the `msgpack` capability the server
+//! sends every message after its `Hello` as binary MessagePack, with the same
+//! field names as the json. Clients may send binary MessagePack messages with
+//! or without it, text messages are always parsed as json.
+//!
+//! The server publishes a JSON Schema of the protocol at `/protocol.schema.json`,
+//! and `static/protocol.d.ts` is regenerated with `extensao --typescript` after
+//! any change to this file.
+
+use std::sync::{Arc, OnceLock};
+
+/// current version of the protocol
+pub const PROTOCOL_VERSION: u32 = 1;
+/// oldest version of the protocol this server still understands
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// optional behaviours a client may ask for in its `Hello`
+pub const CAPABILITIES: &[&str] = &["resync", "msgpack"];
+
+/// the first message of every websocket, sent by the client
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+#[serde(tag = "cmd")]
+pub enum ClientHello {
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+        /// `seq` of the last event seen, with the `resync` capability only the
+        /// events missed since then are sent, instead of the whole room
+        #[serde(default)]
+        #[ts(optional, as = "Option<f64>")]
+        last_seq: Option<u64>,
+    },
+}
+
+/// a `MasterCommand` or `MemberCommand` with an optional `id`, commands with
+/// an `id` are answered with an `Ack`, repeating the same command with the same
+/// `id` and `session` only repeats the `Ack`
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+pub struct Request<C> {
+    #[serde(default)]
+    #[ts(optional, as = "Option<f64>")]
+    pub id: Option<u64>,
+    /// random for every page load, so that a reloaded page, which starts its `id`s
+    /// again, or another tab of the master is never answered with their `Ack`s
+    #[serde(default)]
+    #[ts(optional)]
+    pub session: Option<String>,
+    #[serde(flatten)]
+    pub command: C,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
 #[serde(tag = "cmd")]
 pub enum MasterCommand {
     Start,
@@ -10,20 +83,72 @@ pub enum MasterCommand {
     SetTime { seconds: u32 },
     SetQuestionPool { question_pool: String },
     Kick { sckid: u32 },
+    SetLateJoin { late_join: LateJoin },
+    AcceptJoin { sckid: u32 },
+    RejectJoin { sckid: u32 },
+    Ban { sckid: u32 },
+    Unkick { sckid: u32 },
+    RenameMember { sckid: u32, name: String },
+    MoveMember { sckid: u32, group: bool },
+    SetMemberNote { sckid: u32, note: String },
+    SetNamesLocked { locked: bool },
+}
+
+impl MasterCommand {
+    /// the `cmd` tag, for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            MasterCommand::Start => "Start",
+            MasterCommand::Finish => "Finish",
+            MasterCommand::ExtraTime { .. } => "ExtraTime",
+            MasterCommand::CloseRoom => "CloseRoom",
+            MasterCommand::SetGroupName { .. } => "SetGroupName",
+            MasterCommand::SetGroupColor { .. } => "SetGroupColor",
+            MasterCommand::SetTime { .. } => "SetTime",
+            MasterCommand::SetQuestionPool { .. } => "SetQuestionPool",
+            MasterCommand::Kick { .. } => "Kick",
+            MasterCommand::SetLateJoin { .. } => "SetLateJoin",
+            MasterCommand::AcceptJoin { .. } => "AcceptJoin",
+            MasterCommand::RejectJoin { .. } => "RejectJoin",
+            MasterCommand::Ban { .. } => "Ban",
+            MasterCommand::Unkick { .. } => "Unkick",
+            MasterCommand::RenameMember { .. } => "RenameMember",
+            MasterCommand::MoveMember { .. } => "MoveMember",
+            MasterCommand::SetMemberNote { .. } => "SetMemberNote",
+            MasterCommand::SetNamesLocked { .. } => "SetNamesLocked",
+        }
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
 #[serde(tag = "cmd")]
 pub enum MemberCommand {
     SetName { name: String },
     SetGroup { group: bool },
-    SetPos { x: f32, y: f32, },
+    SetPos { x: f32, y: f32 },
     Answer { question: u32, answer: u32 },
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+impl MemberCommand {
+    /// the `cmd` tag, for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemberCommand::SetName { .. } => "SetName",
+            MemberCommand::SetGroup { .. } => "SetGroup",
+            MemberCommand::SetPos { .. } => "SetPos",
+            MemberCommand::Answer { .. } => "Answer",
+        }
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
 #[serde(tag = "cmd")]
 pub enum ServerCommand {
+    /// reply to the client's `Hello`, `capabilities` are the ones both sides support
+    Hello {
+        protocol_version: u32,
+        capabilities: Vec<String>,
+    },
     Started {
         remaining: u32,
     },
@@ -41,13 +166,20 @@ pub enum ServerCommand {
         group_false_color: String,
         group_true_name: String,
         group_true_color: String,
+        late_join: LateJoin,
+        names_locked: bool,
+        /// longest name accepted by `SetName`, from the config of the server
+        max_name_length: u32,
     },
+    /// the master receives this periodically while answers keep changing,
+    /// between those it only receives `AnswerUpdated`
     AnswersChanged {
         answers: Vec<MemberAnswers>,
     },
     MembersChanged {
         members: Vec<Member>,
     },
+    /// a single answer, `member` already counts it
     AnswerUpdated {
         answer: Answer,
         member: Member,
@@ -55,13 +187,133 @@ pub enum ServerCommand {
     MemberUpdated {
         member: Member,
     },
+    /// the latest position of every member that moved, batched at a fixed rate,
+    /// it has no `seq`, a resync sends the current position of every member instead
+    PositionsChanged {
+        positions: Vec<Position>,
+    },
     MemberRemoved {
         sckid: u32,
     },
+    /// only sent to the master, when a member asks to join a running game
+    JoinRequest {
+        member: Member,
+    },
+    /// only sent to the master, every member waiting for approval, whenever one
+    /// of them joins, is renamed, accepted or rejected
+    JoinRequestsChanged {
+        members: Vec<Member>,
+    },
+    /// only sent to the connection whose command was rejected
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// the `cmd` of the rejected command, if it could be parsed,
+        /// named `command` because `cmd` is the tag of this enum
+        command: Option<String>,
+    },
+    /// only sent to the connection whose command had an `id`, instead of `Error`
+    Ack {
+        #[ts(type = "number")]
+        id: u64,
+        ok: bool,
+        error: Option<ErrorCode>,
+        message: Option<String>,
+    },
+    /// only sent to the master
+    MemberNote {
+        sckid: u32,
+        note: String,
+    },
+    /// only sent to the master
+    KickedChanged {
+        kicked: Vec<KickedMember>,
+    },
+    /// a message from the server's admin, sent to every room
+    Announcement {
+        message: String,
+    },
     RoomClosed,
 }
 
-#[derive(Clone, serde::Serialize, serde::Deserialize)]
+impl ServerCommand {
+    /// the `cmd` tag, for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerCommand::Hello { .. } => "Hello",
+            ServerCommand::Started { .. } => "Started",
+            ServerCommand::Finished { .. } => "Finished",
+            ServerCommand::ExtraTime { .. } => "ExtraTime",
+            ServerCommand::RoomChanged { .. } => "RoomChanged",
+            ServerCommand::AnswersChanged { .. } => "AnswersChanged",
+            ServerCommand::MembersChanged { .. } => "MembersChanged",
+            ServerCommand::AnswerUpdated { .. } => "AnswerUpdated",
+            ServerCommand::MemberUpdated { .. } => "MemberUpdated",
+            ServerCommand::PositionsChanged { .. } => "PositionsChanged",
+            ServerCommand::MemberRemoved { .. } => "MemberRemoved",
+            ServerCommand::JoinRequest { .. } => "JoinRequest",
+            ServerCommand::JoinRequestsChanged { .. } => "JoinRequestsChanged",
+            ServerCommand::Error { .. } => "Error",
+            ServerCommand::Ack { .. } => "Ack",
+            ServerCommand::MemberNote { .. } => "MemberNote",
+            ServerCommand::KickedChanged { .. } => "KickedChanged",
+            ServerCommand::Announcement { .. } => "Announcement",
+            ServerCommand::RoomClosed => "RoomClosed",
+        }
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    ts_rs::TS,
+)]
+pub enum ErrorCode {
+    RoomNotFound,
+    NoFreeRoomCode,
+    MemberNotFound,
+    GameRunning,
+    GameNotRunning,
+    Banned,
+    Kicked,
+    WaitingForApproval,
+    NamesLocked,
+    NameEmpty,
+    NameTooLong,
+    NameBlocked,
+    InvalidPosition,
+    InvalidCommand,
+    IncompatibleProtocol,
+}
+
+/// what happens when someone tries to join a room while the game is running
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Default,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    ts_rs::TS,
+)]
+pub enum LateJoin {
+    #[default]
+    Deny,
+    Allow,
+    /// the master receives a `JoinRequest` and must accept or reject it, the
+    /// member may still choose its name while it waits
+    Approval,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
 pub struct Member {
     pub sckid: u32,
     pub name: String,
@@ -69,20 +321,312 @@ pub struct Member {
     pub x: f32,
     pub y: f32,
     pub answers: u32,
+    pub presence: Presence,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    ts_rs::TS,
+)]
+pub enum Presence {
+    Online,
+    /// lost its connection recently, and is removed if it does not come back soon
+    Away,
+    Offline,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
 pub struct Answer {
     pub question: u32,
     pub answer: u32,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+pub struct Position {
+    pub sckid: u32,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+pub struct KickedMember {
+    pub member: Member,
+    pub banned: bool,
+    /// rfc3339 timestamp of the kick
+    pub kicked_at: String,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
 pub struct MemberAnswers {
     pub member: Member,
     pub answers: Vec<Answer>,
 }
 
+/// a room as returned by the REST api
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+pub struct RoomInfo {
+    pub game: GameStatus,
+    /// seconds left in the game, zero unless it is `Started`
+    pub remaining: u32,
+    pub members: u32,
+    pub game_time: u32,
+    pub question_pool: String,
+    pub group_false_name: String,
+    pub group_false_color: String,
+    pub group_true_name: String,
+    pub group_true_color: String,
+    pub late_join: LateJoin,
+    pub names_locked: bool,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    schemars::JsonSchema,
+    ts_rs::TS,
+)]
+pub enum GameStatus {
+    Idle,
+    Started,
+    Ended,
+}
+
+/// the settings changed by a `PATCH` to the REST api, missing fields are left as they are
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoomUpdate {
+    #[ts(optional)]
+    pub game_time: Option<u32>,
+    #[ts(optional)]
+    pub question_pool: Option<String>,
+    #[ts(optional)]
+    pub group_false_name: Option<String>,
+    #[ts(optional)]
+    pub group_false_color: Option<String>,
+    #[ts(optional)]
+    pub group_true_name: Option<String>,
+    #[ts(optional)]
+    pub group_true_color: Option<String>,
+    #[ts(optional)]
+    pub late_join: Option<LateJoin>,
+    #[ts(optional)]
+    pub names_locked: Option<bool>,
+}
+
+impl RoomUpdate {
+    /// the master commands that apply this update
+    pub fn into_commands(self) -> Vec<MasterCommand> {
+        let mut commands = Vec::new();
+        if let Some(seconds) = self.game_time {
+            commands.push(MasterCommand::SetTime { seconds });
+        }
+        if let Some(question_pool) = self.question_pool {
+            commands.push(MasterCommand::SetQuestionPool { question_pool });
+        }
+        if let Some(name) = self.group_false_name {
+            commands.push(MasterCommand::SetGroupName { group: false, name });
+        }
+        if let Some(color) = self.group_false_color {
+            commands.push(MasterCommand::SetGroupColor {
+                group: false,
+                color,
+            });
+        }
+        if let Some(name) = self.group_true_name {
+            commands.push(MasterCommand::SetGroupName { group: true, name });
+        }
+        if let Some(color) = self.group_true_color {
+            commands.push(MasterCommand::SetGroupColor { group: true, color });
+        }
+        if let Some(late_join) = self.late_join {
+            commands.push(MasterCommand::SetLateJoin { late_join });
+        }
+        if let Some(locked) = self.names_locked {
+            commands.push(MasterCommand::SetNamesLocked { locked });
+        }
+        commands
+    }
+}
+
+/// a room as listed by the admin console
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+pub struct AdminRoom {
+    pub code: String,
+    pub game: GameStatus,
+    /// seconds left in the game, zero unless it is `Started`
+    pub remaining: u32,
+    pub question_pool: String,
+    pub master_online: bool,
+    pub members: Vec<Member>,
+    /// seconds since the last command, the room is removed after an hour
+    pub idle: u32,
+}
+
+/// the state of the server, for the admin console
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+pub struct ServerStatus {
+    pub version: String,
+    /// seconds since the server started
+    pub uptime: u32,
+    pub rooms: u32,
+    pub members_online: u32,
+}
+
+/// the body of a `POST` to `/admin/anuncio`
+#[derive(Clone, serde::Serialize, serde::Deserialize, schemars::JsonSchema, ts_rs::TS)]
+#[serde(deny_unknown_fields)]
+pub struct AnnouncementRequest {
+    pub message: String,
+}
+
+/// JSON Schema of every message of the protocol, served at `/protocol.schema.json`
+pub fn json_schema() -> serde_json::Value {
+    let mut generator = schemars::gen::SchemaSettings::draft07().into_generator();
+    let client = [
+        generator.subschema_for::<ClientHello>(),
+        generator.subschema_for::<Request<MasterCommand>>(),
+        generator.subschema_for::<Request<MemberCommand>>(),
+    ];
+    let server = generator.subschema_for::<ServerCommand>();
+    serde_json::json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "extensao websocket protocol",
+        "protocol_version": PROTOCOL_VERSION,
+        "capabilities": CAPABILITIES,
+        "definitions": generator.take_definitions(),
+        "properties": {
+            "client": { "anyOf": client },
+            // every server message but the replies to a single connection and the
+            // positions has a `seq`
+            "server": {
+                "allOf": [
+                    server,
+                    { "properties": { "seq": { "type": "integer", "minimum": 0 } } },
+                ],
+            },
+        },
+    })
+}
+
+/// TypeScript definitions of every message of the protocol, printed by `--typescript`
+pub fn typescript() -> String {
+    use ts_rs::TS;
+    let decls = [
+        ClientHello::decl(),
+        Request::<()>::decl(),
+        MasterCommand::decl(),
+        MemberCommand::decl(),
+        ServerCommand::decl(),
+        ErrorCode::decl(),
+        LateJoin::decl(),
+        Presence::decl(),
+        Member::decl(),
+        Position::decl(),
+        Answer::decl(),
+        KickedMember::decl(),
+        MemberAnswers::decl(),
+        RoomInfo::decl(),
+        GameStatus::decl(),
+        RoomUpdate::decl(),
+        AdminRoom::decl(),
+        ServerStatus::decl(),
+        AnnouncementRequest::decl(),
+    ];
+    let mut typescript = format!(
+        "// generated from src/command.rs by `extensao --typescript`, do not edit\n\
+         export const PROTOCOL_VERSION = {PROTOCOL_VERSION};\n\n"
+    );
+    for decl in decls {
+        typescript += &format!("export {decl}\n\n");
+    }
+    typescript += "export type ClientMessage = ClientHello | Request<MasterCommand> | Request<MemberCommand>;\n\n";
+    typescript += "export type ServerMessage = { seq?: number } & ServerCommand;\n";
+    typescript
+}
+
+/// how the messages of a connection are encoded
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+}
+
+/// a `ServerCommand` queued for one or more connections
+pub struct Event {
+    /// position in the room's event log, `None` for replies to a single connection
+    /// and for `PositionsChanged`, which is not replayed
+    pub seq: Option<u64>,
+    pub command: ServerCommand,
+    json: OnceLock<String>,
+    msgpack: OnceLock<Vec<u8>>,
+}
+
+#[derive(serde::Serialize)]
+struct Sequenced<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(flatten)]
+    command: &'a ServerCommand,
+}
+
+impl Event {
+    pub fn new(seq: Option<u64>, command: ServerCommand) -> Arc<Self> {
+        Arc::new(Self {
+            seq,
+            command,
+            json: OnceLock::new(),
+            msgpack: OnceLock::new(),
+        })
+    }
+    /// the json is only serialized once, no matter how many connections receive it
+    pub fn to_json(&self) -> &str {
+        self.json.get_or_init(|| {
+            let sequenced = Sequenced {
+                seq: self.seq,
+                command: &self.command,
+            };
+            serde_json::to_string(&sequenced)
+                .expect("ServerCommand is always serializable into json")
+        })
+    }
+    pub fn to_msgpack(&self) -> &[u8] {
+        self.msgpack.get_or_init(|| {
+            let sequenced = Sequenced {
+                seq: self.seq,
+                command: &self.command,
+            };
+            // named, so that fields are maps keyed like the json, not positional arrays
+            rmp_serde::to_vec_named(&sequenced)
+                .expect("ServerCommand is always serializable into messagepack")
+        })
+    }
+    pub fn to_message(&self, encoding: Encoding) -> warp::ws::Message {
+        match encoding {
+            Encoding::Json => warp::ws::Message::text(self.to_json()),
+            Encoding::MessagePack => warp::ws::Message::binary(self.to_msgpack()),
+        }
+    }
+    /// for the server-sent events transport, `seq` is also the event id, so that the
+    /// browser sends it back as `Last-Event-ID` when it reconnects
+    pub fn to_sse(&self) -> warp::sse::Event {
+        let event = warp::sse::Event::default().data(self.to_json());
+        match self.seq {
+            Some(seq) => event.id(seq.to_string()),
+            None => event,
+        }
+    }
+}
+
 impl From<ServerCommand> for warp::ws::Message {
     fn from(value: ServerCommand) -> Self {
         let json =
@@ -90,3 +634,88 @@ impl From<ServerCommand> for warp::ws::Message {
         warp::ws::Message::text(json)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an `Event` as the client reads it
+    #[derive(serde::Deserialize)]
+    struct Received {
+        seq: Option<u64>,
+        #[serde(flatten)]
+        command: ServerCommand,
+    }
+
+    #[test]
+    fn msgpack_requests_round_trip() {
+        let request = Request {
+            id: Some(1 << 40),
+            session: Some("abc".to_owned()),
+            command: MemberCommand::SetPos { x: 0.25, y: -1.5 },
+        };
+        let bytes = rmp_serde::to_vec_named(&request).unwrap();
+        let decoded: Request<MemberCommand> = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.id, Some(1 << 40));
+        assert_eq!(decoded.session.as_deref(), Some("abc"));
+        let MemberCommand::SetPos { x, y } = decoded.command else {
+            panic!("expected SetPos");
+        };
+        assert_eq!((x, y), (0.25, -1.5));
+
+        // browsers encode every number that is not an integer as a float64
+        let bytes = rmp_serde::to_vec_named(&serde_json::json!({
+            "cmd": "SetPos",
+            "x": 0.5,
+            "y": 2.0,
+        }))
+        .unwrap();
+        let decoded: Request<MemberCommand> = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded.id, None);
+        assert_eq!(decoded.session, None);
+        let MemberCommand::SetPos { x, y } = decoded.command else {
+            panic!("expected SetPos");
+        };
+        assert_eq!((x, y), (0.5, 2.0));
+    }
+
+    #[test]
+    fn msgpack_events_round_trip() {
+        let positions = Event::new(
+            None,
+            ServerCommand::PositionsChanged {
+                // exact in f32 and f64, so the json below compares equal
+                positions: vec![Position {
+                    sckid: 3,
+                    x: 0.375,
+                    y: -0.75,
+                }],
+            },
+        );
+        let received: Received = rmp_serde::from_slice(positions.to_msgpack()).unwrap();
+        assert_eq!(received.seq, None);
+        let ServerCommand::PositionsChanged { positions: decoded } = &received.command else {
+            panic!("expected PositionsChanged");
+        };
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(
+            (decoded[0].sckid, decoded[0].x, decoded[0].y),
+            (3, 0.375, -0.75)
+        );
+
+        let extra_time = Event::new(Some(u64::MAX), ServerCommand::ExtraTime { seconds: 10 });
+        let received: Received = rmp_serde::from_slice(extra_time.to_msgpack()).unwrap();
+        assert_eq!(received.seq, Some(u64::MAX));
+        assert!(matches!(
+            received.command,
+            ServerCommand::ExtraTime { seconds: 10 }
+        ));
+
+        // the same fields as the json
+        for event in [positions, extra_time] {
+            let received: serde_json::Value = rmp_serde::from_slice(event.to_msgpack()).unwrap();
+            let json: serde_json::Value = serde_json::from_str(event.to_json()).unwrap();
+            assert_eq!(received, json);
+        }
+    }
+}
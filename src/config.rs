@@ -0,0 +1,823 @@
+//! The config file, `extensao.json`, and its validation.
+//!
+//! The file is read field by field, so that every problem is reported at once
+//! with its line and column, both by `extensao check-config` and when the server
+//! starts, which refuses to start if there is any error. A field with an error is
+//! replaced by its default, so the remaining fields can still be checked.
+
+use serde_json::{Map, Value};
+use std::{
+    collections::BTreeMap,
+    io::ErrorKind,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use crate::cli::Options;
+
+pub const CONFIG_FILE: &str = "extensao.json";
+
+/// bearer tokens shorter than this are reported as easy to guess
+const MIN_TOKEN_LENGTH: usize = 16;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Config {
+    /// an IPv4 or IPv6 address or a hostname, every interface if empty
+    pub ip: String,
+    pub port: u16,
+    pub domain: String,
+    pub base: String,
+    pub tls: bool,
+    pub cert: String,
+    pub key: String,
+    /// addresses to listen on, each with its own port and tls, `ip`, `port` and `tls`
+    /// are only used if this is empty
+    #[serde(default)]
+    pub listen: Vec<Listener>,
+    #[serde(default)]
+    pub names: crate::state::NameRules,
+    #[serde(default)]
+    pub heartbeat: crate::state::Heartbeat,
+    /// bearer tokens accepted by the REST api, the api is disabled if empty
+    #[serde(default)]
+    pub api_tokens: Vec<String>,
+    /// bearer tokens accepted by the admin console, the console is disabled if empty
+    #[serde(default)]
+    pub admin_tokens: Vec<String>,
+    #[serde(default)]
+    pub webhooks: Vec<crate::webhook::Webhook>,
+    #[serde(default)]
+    pub lti: crate::lti::Lti,
+    /// how often the buffered member positions are sent to the rooms
+    #[serde(default = "default_positions_interval_ms")]
+    pub positions_interval_ms: u64,
+    #[serde(default)]
+    pub logging: crate::logging::Logging,
+}
+
+/// all the listeners with tls share the `cert` and `key` of the config
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Listener {
+    pub ip: String,
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,
+}
+
+fn default_positions_interval_ms() -> u64 {
+    100
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ip: "0.0.0.0".to_owned(),
+            port: 80,
+            domain: "127.0.0.1".to_owned(),
+            base: "/".to_owned(),
+            tls: false,
+            cert: "tls/cert.pem".to_owned(),
+            key: "tls/key.rsa".to_owned(),
+            listen: Vec::new(),
+            names: Default::default(),
+            heartbeat: Default::default(),
+            api_tokens: Vec::new(),
+            admin_tokens: Vec::new(),
+            webhooks: Vec::new(),
+            lti: Default::default(),
+            positions_interval_ms: default_positions_interval_ms(),
+            logging: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    /// the `listen` list, or the single listener of `ip`, `port` and `tls`
+    pub fn listeners(&self) -> Vec<Listener> {
+        if self.listen.is_empty() {
+            vec![Listener {
+                ip: self.ip.clone(),
+                port: self.port,
+                tls: self.tls,
+            }]
+        } else {
+            self.listen.clone()
+        }
+    }
+}
+
+impl Options {
+    /// the command line options and environment variables override the config file
+    fn apply(&self, config: &mut Config) {
+        // a single listener replaces the list
+        if self.bind.is_some() || self.port.is_some() || self.tls.is_some() {
+            config.listen.clear();
+        }
+        let replace = |field: &mut String, value: &Option<String>| {
+            if let Some(value) = value {
+                field.clone_from(value);
+            }
+        };
+        replace(&mut config.ip, &self.bind);
+        replace(&mut config.cert, &self.tls_cert);
+        replace(&mut config.key, &self.tls_key);
+        replace(&mut config.domain, &self.domain);
+        replace(&mut config.base, &self.base);
+        replace(&mut config.logging.level, &self.log_level);
+        replace(&mut config.logging.file, &self.log_file);
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(tls) = self.tls {
+            config.tls = tls;
+        }
+    }
+    /// the fields of the config file set by the options, with the option or variable
+    /// that set them, they may be missing from the file
+    fn overridden(&self) -> BTreeMap<String, String> {
+        [
+            ("ip", "bind"),
+            ("port", "port"),
+            ("tls", "tls"),
+            ("cert", "tls-cert"),
+            ("key", "tls-key"),
+            ("domain", "domain"),
+            ("base", "base"),
+            ("logging.level", "log-level"),
+            ("logging.file", "log-file"),
+        ]
+        .into_iter()
+        .filter_map(|(field, option)| Some((field.to_owned(), self.origins.get(option)?.clone())))
+        .collect()
+    }
+}
+
+/// a problem in the config file
+pub struct Diagnostic {
+    /// line and column in the file, `None` for values that are not in the file,
+    /// like the ones given on the command line or by an environment variable
+    pub position: Option<(usize, usize)>,
+    /// like `listen.1.ip`, empty for the whole file
+    pub field: String,
+    pub message: String,
+    /// warnings do not stop the server from starting
+    pub warning: bool,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some((line, column)) = self.position {
+            write!(f, "{line}:{column}: ")?;
+        }
+        write!(f, "{}: ", if self.warning { "warning" } else { "error" })?;
+        if !self.field.is_empty() {
+            write!(f, "{}: ", self.field)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+struct Diagnostics {
+    positions: BTreeMap<String, (usize, usize)>,
+    /// the fields replaced by the options, by the option or variable, only set once
+    /// the file was read, since the problems of the file itself are still in the file
+    overridden: BTreeMap<String, String>,
+    list: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    fn push(&mut self, field: &str, message: String, warning: bool) {
+        let (position, message) = match self.overridden.get(field) {
+            Some(origin) => (None, format!("{message} (set by {origin})")),
+            None => (self.positions.get(field).copied(), message),
+        };
+        self.list.push(Diagnostic {
+            position,
+            field: field.to_owned(),
+            message,
+            warning,
+        });
+    }
+    fn error(&mut self, field: &str, message: impl Into<String>) {
+        self.push(field, message.into(), false);
+    }
+    fn warning(&mut self, field: &str, message: impl Into<String>) {
+        self.push(field, message.into(), true);
+    }
+    fn check(&mut self, field: &str, result: Result<(), String>) {
+        if let Err(message) = result {
+            self.error(field, message);
+        }
+    }
+}
+
+/// the config file, `extensao.json` next to the executable by default, in release
+/// builds this also changes into its folder, so that the paths in it are relative to it
+pub fn locate(options: &Options) -> Result<PathBuf, Diagnostic> {
+    #[cfg(not(debug_assertions))]
+    {
+        let error = |message: String| Diagnostic {
+            position: None,
+            field: String::new(),
+            message,
+            warning: false,
+        };
+        let config_file = match &options.config {
+            Some(path) => std::path::absolute(path),
+            None => std::env::current_exe().map(|x| x.with_file_name(CONFIG_FILE)),
+        }
+        .map_err(|x| error(format!("could not find the config file: {x}")))?;
+        let folder = config_file.parent().unwrap_or(Path::new("/"));
+        std::env::set_current_dir(folder).map_err(|x| {
+            error(format!(
+                "could not change into the folder of the config file \"{}\": {x}",
+                folder.display()
+            ))
+        })?;
+        Ok(config_file)
+    }
+    #[cfg(debug_assertions)]
+    Ok(options.config.clone().unwrap_or_else(|| CONFIG_FILE.into()))
+}
+
+/// reads the config file and applies the options on top of it, a missing file is
+/// created with the defaults if `create` is set, the config is only valid if none of
+/// the diagnostics is an error
+pub fn load(path: &Path, options: &Options, create: bool) -> (Config, Vec<Diagnostic>) {
+    let overridden = options.overridden();
+    let mut diagnostics = Diagnostics {
+        positions: BTreeMap::new(),
+        overridden: BTreeMap::new(),
+        list: Vec::new(),
+    };
+    let mut config = read(path, create, &overridden, &mut diagnostics).unwrap_or_default();
+    options.apply(&mut config);
+    diagnostics.overridden = overridden;
+    validate(&config, &mut diagnostics);
+    (config, diagnostics.list)
+}
+
+/// `extensao check-config`, prints every problem of the config file
+pub fn check(options: &Options) -> bool {
+    let path = match locate(options) {
+        Ok(path) => path,
+        Err(diagnostic) => {
+            println!("{diagnostic}");
+            return false;
+        }
+    };
+    let (_, diagnostics) = load(&path, options, false);
+    for variable in &options.ignored {
+        println!("warning: {variable}: not an option, the environment variable was ignored");
+    }
+    for diagnostic in &diagnostics {
+        println!("{}:{diagnostic}", path.display());
+    }
+    let errors = diagnostics.iter().filter(|x| !x.warning).count();
+    if errors == 0 {
+        println!("{}: ok", path.display());
+    } else {
+        println!("{}: {errors} error(s)", path.display());
+    }
+    errors == 0
+}
+
+fn read(
+    path: &Path,
+    create: bool,
+    overridden: &BTreeMap<String, String>,
+    diagnostics: &mut Diagnostics,
+) -> Option<Config> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            if !create {
+                diagnostics.warning("", "the file does not exist, the defaults are used");
+                return None;
+            }
+            let default_config_file = serde_json::to_string_pretty(&Config::default()).unwrap();
+            if let Err(error) = std::fs::write(path, default_config_file) {
+                diagnostics.error(
+                    "",
+                    format!("the file does not exist and could not be created: {error}"),
+                );
+            }
+            return None;
+        }
+        Err(error) => {
+            diagnostics.error("", format!("could not read the file: {error}"));
+            return None;
+        }
+    };
+    let file: Value = match serde_json::from_str(&text) {
+        Ok(file) => file,
+        Err(error) => {
+            let position = format!(" at line {} column {}", error.line(), error.column());
+            let message = error.to_string();
+            diagnostics.list.push(Diagnostic {
+                position: Some((error.line(), error.column())),
+                field: String::new(),
+                message: format!("not valid json: {}", message.trim_end_matches(&position)),
+                warning: false,
+            });
+            return None;
+        }
+    };
+    diagnostics.positions = positions(&text);
+    let Value::Object(file) = file else {
+        diagnostics.error("", "the file must be a json object");
+        return None;
+    };
+    let Value::Object(defaults) = serde_json::to_value(Config::default()).unwrap() else {
+        unreachable!("Config is a struct");
+    };
+    // each field is checked alone, on top of the defaults
+    let parse =
+        |fields: Map<String, Value>| serde_json::from_value::<Config>(Value::Object(fields));
+    let mut merged = defaults.clone();
+    for (field, value) in &file {
+        if !defaults.contains_key(field) {
+            diagnostics.warning(field, "unknown field, it is ignored");
+            continue;
+        }
+        let mut fields = defaults.clone();
+        fields.insert(field.clone(), value.clone());
+        match parse(fields) {
+            Ok(_) => {
+                merged.insert(field.clone(), value.clone());
+            }
+            Err(error) => diagnostics.error(field, error.to_string()),
+        }
+    }
+    let missing = defaults
+        .keys()
+        .filter(|x| !file.contains_key(*x) && !overridden.contains_key(*x));
+    for field in missing {
+        let mut fields = defaults.clone();
+        fields.remove(field);
+        if parse(fields).is_err() {
+            diagnostics.error("", format!("missing field `{field}`"));
+        }
+    }
+    Some(parse(merged).expect("every field was checked on its own"))
+}
+
+fn validate(config: &Config, diagnostics: &mut Diagnostics) {
+    let listeners = if config.listen.is_empty() {
+        vec![(String::new(), config.listeners().remove(0))]
+    } else {
+        config
+            .listen
+            .iter()
+            .enumerate()
+            .map(|(index, listener)| (format!("listen.{index}."), listener.clone()))
+            .collect()
+    };
+    for (prefix, listener) in &listeners {
+        let result = crate::server::listen_addrs(&listener.ip, listener.port).map(|_| ());
+        diagnostics.check(&format!("{prefix}ip"), result);
+        if listener.port == 0 {
+            diagnostics.error(&format!("{prefix}port"), "the port must not be zero");
+        }
+    }
+    diagnostics.check("domain", check_domain(&config.domain));
+    diagnostics.check("base", check_base(&config.base));
+    if listeners.iter().any(|(_, listener)| listener.tls) {
+        diagnostics.check("cert", crate::health::check_cert(&config.cert));
+        diagnostics.check("key", crate::health::check_key(&config.key));
+    }
+
+    if config.names.max_length == 0 {
+        diagnostics.error("names.max_length", "every name would be rejected");
+    } else if config.names.disambiguate
+        && config.names.max_length < crate::state::MIN_DISAMBIGUATED_LENGTH
+    {
+        diagnostics.error(
+            "names.max_length",
+            format!(
+                "must be at least {} to add a number to repeated names, or disambiguate must be false",
+                crate::state::MIN_DISAMBIGUATED_LENGTH
+            ),
+        );
+    }
+    let heartbeat = &config.heartbeat;
+    if heartbeat.ping_timeout_secs <= heartbeat.ping_interval_secs {
+        diagnostics.error(
+            "heartbeat.ping_timeout_secs",
+            "must be greater than ping_interval_secs, or quiet connections are closed between pings",
+        );
+    }
+    if config.positions_interval_ms == 0 {
+        diagnostics.warning("positions_interval_ms", "zero is treated as 1");
+    }
+
+    for (field, tokens) in [
+        ("api_tokens", &config.api_tokens),
+        ("admin_tokens", &config.admin_tokens),
+    ] {
+        for (index, token) in tokens.iter().enumerate() {
+            let field = format!("{field}.{index}");
+            if token.trim().is_empty() {
+                diagnostics.error(&field, "an empty token would accept any request");
+            } else if token.trim() != token {
+                diagnostics.error(&field, "tokens must not start or end with spaces");
+            } else if token.chars().count() < MIN_TOKEN_LENGTH {
+                diagnostics.warning(
+                    &field,
+                    format!("shorter than {MIN_TOKEN_LENGTH} characters, easy to guess"),
+                );
+            }
+        }
+    }
+
+    for (index, webhook) in config.webhooks.iter().enumerate() {
+        let field = format!("webhooks.{index}");
+        diagnostics.check(&format!("{field}.url"), check_url(&webhook.url));
+        for (event_index, event) in webhook.events.iter().enumerate() {
+            if !crate::webhook::EVENTS.contains(&event.as_str()) {
+                diagnostics.error(
+                    &format!("{field}.events.{event_index}"),
+                    format!(
+                        "unknown event \"{event}\", the events are {}",
+                        crate::webhook::EVENTS.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+
+    for (index, platform) in config.lti.platforms.iter().enumerate() {
+        let field = format!("lti.platforms.{index}");
+        for (name, url) in [
+            ("auth_url", &platform.auth_url),
+            ("token_url", &platform.token_url),
+            ("jwks_url", &platform.jwks_url),
+        ] {
+            diagnostics.check(&format!("{field}.{name}"), check_url(url));
+        }
+    }
+    if !config.lti.private_key.is_empty() {
+        let result = std::fs::read(&config.lti.private_key)
+            .map_err(|error| format!("could not read \"{}\": {error}", config.lti.private_key))
+            .and_then(|pem| {
+                jsonwebtoken::EncodingKey::from_rsa_pem(&pem)
+                    .map(|_| ())
+                    .map_err(|error| format!("not a valid RSA pem: {error}"))
+            });
+        diagnostics.check("lti.private_key", result);
+    }
+    for (pool, answers) in &config.lti.answer_keys {
+        if answers.iter().any(|answer| !(1..=5).contains(answer)) {
+            diagnostics.error(
+                &format!("lti.answer_keys.{pool}"),
+                "the answers go from 1 to 5",
+            );
+        }
+    }
+
+    let logging = &config.logging;
+    if let Err(error) = tracing_subscriber::EnvFilter::try_new(&logging.level) {
+        diagnostics.error("logging.level", format!("not a valid level: {error}"));
+    }
+    if !logging.file.is_empty() {
+        let directory = Path::new(&logging.file)
+            .parent()
+            .filter(|x| !x.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if !directory.is_dir() {
+            diagnostics.error(
+                "logging.file",
+                format!("the folder \"{}\" does not exist", directory.display()),
+            );
+        }
+    }
+}
+
+/// the host of the urls in the qr codes
+fn check_domain(domain: &str) -> Result<(), String> {
+    if domain.is_empty() {
+        return Err("the domain is empty, the qr codes would have no host".to_owned());
+    }
+    if let Some(ip) = domain.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+        return match ip.parse::<Ipv6Addr>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("\"{ip}\" is not an IPv6 address")),
+        };
+    }
+    if domain.parse::<Ipv6Addr>().is_ok() {
+        return Err(format!(
+            "IPv6 addresses go in brackets in urls, like [{domain}]"
+        ));
+    }
+    if domain.parse::<Ipv4Addr>().is_ok() {
+        return Ok(());
+    }
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && label.chars().all(|x| x.is_ascii_alphanumeric() || x == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+    };
+    if domain.len() > 253 || !domain.split('.').all(valid_label) {
+        return Err(format!("\"{domain}\" is not a valid hostname"));
+    }
+    Ok(())
+}
+
+/// the path the site is served under, used in cookies and redirects
+fn check_base(base: &str) -> Result<(), String> {
+    if !base.starts_with('/') || !base.ends_with('/') {
+        return Err(format!(
+            "\"{base}\" must start and end with a /, like / or /extensao/"
+        ));
+    }
+    if base.contains("//") {
+        return Err(format!("\"{base}\" has an empty segment"));
+    }
+    let invalid = |x: char| x.is_whitespace() || x.is_control() || matches!(x, '?' | '#' | ';');
+    if base.contains(invalid) || base.parse::<warp::http::uri::PathAndQuery>().is_err() {
+        return Err(format!("\"{base}\" is not a plain url path"));
+    }
+    Ok(())
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        Ok(_) => Err(format!("\"{url}\" is not an http or https url")),
+        Err(error) => Err(format!("\"{url}\" is not a valid url: {error}")),
+    }
+}
+
+/// the line and column of every value of a valid json document, by their path
+/// with the keys and indexes separated by dots, the path of the document is empty
+fn positions(text: &str) -> BTreeMap<String, (usize, usize)> {
+    let mut scanner = Scanner {
+        chars: text.chars().peekable(),
+        line: 1,
+        column: 1,
+        positions: BTreeMap::new(),
+    };
+    scanner.value(String::new());
+    scanner.positions
+}
+
+struct Scanner<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+    positions: BTreeMap<String, (usize, usize)>,
+}
+
+impl Scanner<'_> {
+    fn bump(&mut self) -> Option<char> {
+        let char = self.chars.next()?;
+        if char == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(char)
+    }
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().is_some_and(|x| x.is_whitespace()) {
+            self.bump();
+        }
+    }
+    fn value(&mut self, path: String) {
+        self.skip_whitespace();
+        self.positions
+            .insert(path.clone(), (self.line, self.column));
+        let child = |key: &dyn std::fmt::Display| {
+            if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{path}.{key}")
+            }
+        };
+        match self.chars.peek() {
+            Some('{') => {
+                self.bump();
+                loop {
+                    self.skip_whitespace();
+                    match self.chars.peek() {
+                        Some('"') => {
+                            let key = self.string();
+                            self.skip_whitespace();
+                            // the colon
+                            self.bump();
+                            self.value(child(&key));
+                        }
+                        Some(',') => {
+                            self.bump();
+                        }
+                        _ => {
+                            self.bump();
+                            break;
+                        }
+                    }
+                }
+            }
+            Some('[') => {
+                self.bump();
+                let mut index = 0;
+                loop {
+                    self.skip_whitespace();
+                    match self.chars.peek() {
+                        Some(',') => {
+                            self.bump();
+                            index += 1;
+                        }
+                        Some(']') | None => {
+                            self.bump();
+                            break;
+                        }
+                        Some(_) => self.value(child(&index)),
+                    }
+                }
+            }
+            Some('"') => {
+                self.string();
+            }
+            _ => {
+                while self
+                    .chars
+                    .peek()
+                    .is_some_and(|x| !x.is_whitespace() && !matches!(x, ',' | '}' | ']'))
+                {
+                    self.bump();
+                }
+            }
+        }
+    }
+    /// the escapes are kept as the escaped character, good enough for keys
+    fn string(&mut self) -> String {
+        self.bump();
+        let mut string = String::new();
+        while let Some(char) = self.bump() {
+            match char {
+                '"' => break,
+                '\\' => string.extend(self.bump()),
+                char => string.push(char),
+            }
+        }
+        string
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a diagnostic as its `field`, `line:col` and whether it is a warning
+    type Found = (String, Option<(usize, usize)>, bool);
+
+    /// the diagnostics of a config file with this text
+    fn load_text(name: &str, text: &str, options: &Options) -> Vec<Diagnostic> {
+        let path =
+            std::env::temp_dir().join(format!("extensao-{}-{name}.json", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let (_, diagnostics) = load(&path, options, false);
+        std::fs::remove_file(&path).unwrap();
+        diagnostics
+    }
+
+    fn diagnose(name: &str, text: &str) -> Vec<Found> {
+        load_text(name, text, &Options::default())
+            .into_iter()
+            .map(|x| (x.field, x.position, x.warning))
+            .collect()
+    }
+
+    #[test]
+    fn scanner_positions() {
+        let text = concat!(
+            "{\n",
+            "  \"a\": 1,\n",
+            "  \"b\": {\"c\": [true, {\"d\": \"x,}]\"}], \"e\\\"f\": null},\n",
+            "\t\"g\": []\n",
+            "}",
+        );
+        let expected = [
+            ("", (1, 1)),
+            ("a", (2, 8)),
+            ("b", (3, 8)),
+            ("b.c", (3, 14)),
+            ("b.c.0", (3, 15)),
+            ("b.c.1", (3, 21)),
+            ("b.c.1.d", (3, 27)),
+            ("b.e\"f", (3, 45)),
+            ("g", (4, 7)),
+        ];
+        let expected: BTreeMap<_, _> = expected
+            .into_iter()
+            .map(|(path, position)| (path.to_owned(), position))
+            .collect();
+        assert_eq!(positions(text), expected);
+    }
+
+    #[test]
+    fn validation_errors_point_at_the_field() {
+        let text = r#"{
+  "ip": "0.0.0.0",
+  "port": 80,
+  "domain": "-bad-",
+  "base": "/",
+  "tls": false,
+  "cert": "",
+  "key": "",
+  "listen": [
+    { "ip": "127.0.0.1", "port": 8080 },
+    { "ip": "127.0.0.1", "port": 0 }
+  ],
+  "heartbeat": { "ping_interval_secs": 30, "ping_timeout_secs": 10 },
+  "api_tokens": ["short"],
+  "webhooks": [
+    { "url": "https://example.com/" },
+    { "url": "ftp://example.com/", "events": ["RoomCreated", "Nope"] }
+  ],
+  "unknown": 1
+}"#;
+        assert_eq!(
+            diagnose("validation", text),
+            [
+                ("unknown".to_owned(), Some((19, 14)), true),
+                ("listen.1.port".to_owned(), Some((11, 34)), false),
+                ("domain".to_owned(), Some((4, 13)), false),
+                (
+                    "heartbeat.ping_timeout_secs".to_owned(),
+                    Some((13, 65)),
+                    false
+                ),
+                ("api_tokens.0".to_owned(), Some((14, 18)), true),
+                ("webhooks.1.url".to_owned(), Some((17, 14)), false),
+                ("webhooks.1.events.1".to_owned(), Some((17, 62)), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors_point_at_the_field() {
+        let text = r#"{
+  "ip": "0.0.0.0",
+  "port": "80",
+  "domain": "127.0.0.1",
+  "base": "/",
+  "tls": false,
+  "cert": "",
+  "listen": [{ "ip": "127.0.0.1" }]
+}"#;
+        // a field that can not be parsed is reported as a whole, and a missing
+        // field at the start of the document
+        assert_eq!(
+            diagnose("parse", text),
+            [
+                ("listen".to_owned(), Some((8, 13)), false),
+                ("port".to_owned(), Some((3, 11)), false),
+                (String::new(), Some((1, 1)), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_json_points_at_the_error() {
+        assert_eq!(
+            diagnose("json", "{\n  \"ip\": \"0.0.0.0\",\n  \"port\": 80,,\n}"),
+            [(String::new(), Some((3, 14)), false)]
+        );
+    }
+
+    #[test]
+    fn overridden_values_are_not_in_the_file() {
+        let text = r#"{
+  "ip": "0.0.0.0",
+  "port": 80,
+  "domain": "127.0.0.1",
+  "base": "/",
+  "tls": false,
+  "cert": "",
+  "key": "",
+  "listen": [{ "ip": "127.0.0.1", "port": 0 }]
+}"#;
+        let options = Options {
+            port: Some(0),
+            base: Some("base".to_owned()),
+            origins: [("port", "--port"), ("base", "EXTENSAO_BASE")]
+                .map(|(option, origin)| (option.to_owned(), origin.to_owned()))
+                .into(),
+            ..Default::default()
+        };
+        let diagnostics: Vec<_> = load_text("overridden", text, &options)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            diagnostics,
+            [
+                "error: port: the port must not be zero (set by --port)",
+                "error: base: \"base\" must start and end with a /, like / or /extensao/ (set by EXTENSAO_BASE)",
+            ]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+//! Health and readiness, served as json by `GET /healthz` and `GET /readyz`.
+//!
+//! `/healthz` fails when the one second tick loop stops running, `/readyz` also
+//! fails before the config is loaded, when the TLS files can no longer be read and
+//! once the server starts shutting down. Both reply 503 when they fail.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// the tick loop is considered stalled after missing this many seconds of ticks
+const TICK_TIMEOUT: Duration = Duration::from_secs(5);
+
+static LAST_TICK: Mutex<Option<Instant>> = Mutex::new(None);
+static CONFIG_LOADED: AtomicBool = AtomicBool::new(false);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+/// the certificate and key, checked again by every readiness probe
+static TLS_FILES: OnceLock<(String, String)> = OnceLock::new();
+
+#[derive(serde::Serialize)]
+pub struct Health {
+    pub ok: bool,
+    /// milliseconds since the tick loop last ran, `None` before its first tick
+    pub last_tick_ms: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Readiness {
+    pub ok: bool,
+    pub ticking: bool,
+    pub config_loaded: bool,
+    /// always true without TLS
+    pub tls: bool,
+    pub shutting_down: bool,
+}
+
+/// called by the tick loop after every `periodic_routine`
+pub fn ticked() {
+    *LAST_TICK.lock().unwrap() = Some(Instant::now());
+}
+
+pub fn config_loaded(tls: Option<(String, String)>) {
+    if let Some(files) = tls {
+        let _ = TLS_FILES.set(files);
+    }
+    CONFIG_LOADED.store(true, Ordering::Relaxed);
+}
+
+pub fn shutting_down() {
+    SHUTTING_DOWN.store(true, Ordering::Relaxed);
+}
+
+/// checks that the certificate and key files can be read and hold a certificate and a
+/// key, a renewal that left them broken would only show up on the next restart otherwise
+pub fn check_tls(cert: &str, key: &str) -> Result<(), String> {
+    check_cert(cert)?;
+    check_key(key)
+}
+
+pub fn check_cert(path: &str) -> Result<(), String> {
+    let items = read_pem(path)?;
+    if !items
+        .iter()
+        .any(|x| matches!(x, rustls_pemfile::Item::X509Certificate(_)))
+    {
+        return Err(format!("\"{path}\" has no certificate"));
+    }
+    Ok(())
+}
+
+pub fn check_key(path: &str) -> Result<(), String> {
+    use rustls_pemfile::Item;
+    let items = read_pem(path)?;
+    if !items
+        .iter()
+        .any(|x| matches!(x, Item::RSAKey(_) | Item::PKCS8Key(_) | Item::ECKey(_)))
+    {
+        return Err(format!("\"{path}\" has no private key"));
+    }
+    Ok(())
+}
+
+fn read_pem(path: &str) -> Result<Vec<rustls_pemfile::Item>, String> {
+    let file =
+        std::fs::File::open(path).map_err(|error| format!("could not read \"{path}\": {error}"))?;
+    rustls_pemfile::read_all(&mut std::io::BufReader::new(file))
+        .map_err(|error| format!("\"{path}\" is not a valid pem file: {error}"))
+}
+
+pub fn health() -> Health {
+    let last_tick = LAST_TICK.lock().unwrap().map(|tick| tick.elapsed());
+    Health {
+        ok: last_tick.is_some_and(|elapsed| elapsed < TICK_TIMEOUT),
+        last_tick_ms: last_tick.map(|elapsed| elapsed.as_millis() as u64),
+    }
+}
+
+pub fn readiness() -> Readiness {
+    let ticking = health().ok;
+    let config_loaded = CONFIG_LOADED.load(Ordering::Relaxed);
+    let tls = TLS_FILES
+        .get()
+        .is_none_or(|(cert, key)| check_tls(cert, key).is_ok());
+    let shutting_down = SHUTTING_DOWN.load(Ordering::Relaxed);
+    Readiness {
+        ok: ticking && config_loaded && tls && !shutting_down,
+        ticking,
+        config_loaded,
+        tls,
+        shutting_down,
+    }
+}
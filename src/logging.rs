@@ -0,0 +1,96 @@
+//! Logging, configured by the `logging` field of the config file.
+//!
+//! Logs go to the standard output, or to a file rotated by time when running as a
+//! service, as text or as one json object per line. Events about a room carry the
+//! room code in the `room` field, websocket events are in a `connection` span with
+//! the room and sckid. `RUST_LOG` overrides `level` when it is set.
+
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Logging {
+    /// minimum level, or a filter like `info,extensao::api=debug`
+    pub level: String,
+    pub format: LogFormat,
+    /// the logs go to the standard output if empty
+    pub file: String,
+    pub rotation: LogRotation,
+    /// how many rotated files are kept, all of them if zero
+    pub max_files: usize,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            level: "info".to_owned(),
+            format: LogFormat::Text,
+            file: String::new(),
+            rotation: LogRotation::Daily,
+            max_files: 7,
+        }
+    }
+}
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub enum LogFormat {
+    Text,
+    /// one json object per line
+    Json,
+}
+
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// installs the global logger, the logs are written in the background until the
+/// returned guard is dropped, so it must live until the server stops
+pub fn init(logging: &Logging) -> Result<WorkerGuard, String> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(filter) => EnvFilter::try_new(filter),
+        Err(_) => EnvFilter::try_new(&logging.level),
+    }
+    .map_err(|error| format!("invalid log level \"{}\": {error}", logging.level))?;
+    let (writer, guard) = if logging.file.is_empty() {
+        tracing_appender::non_blocking(std::io::stdout())
+    } else {
+        let path = std::path::Path::new(&logging.file);
+        let directory = path.parent().unwrap_or(std::path::Path::new(""));
+        let prefix = path
+            .file_name()
+            .ok_or_else(|| format!("log file \"{}\" is not a file name", logging.file))?
+            .to_string_lossy();
+        let rotation = match logging.rotation {
+            LogRotation::Hourly => rolling::Rotation::HOURLY,
+            LogRotation::Daily => rolling::Rotation::DAILY,
+            LogRotation::Never => rolling::Rotation::NEVER,
+        };
+        let mut appender = rolling::Builder::new()
+            .rotation(rotation)
+            .filename_prefix(prefix);
+        if logging.max_files > 0 {
+            appender = appender.max_log_files(logging.max_files);
+        }
+        let appender = appender
+            .build(directory)
+            .map_err(|error| format!("could not open log file \"{}\": {error}", logging.file))?;
+        tracing_appender::non_blocking(appender)
+    };
+    let layer = match logging.format {
+        LogFormat::Text => fmt::layer()
+            .with_writer(writer)
+            .with_ansi(logging.file.is_empty())
+            .boxed(),
+        LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(layer)
+        .try_init()
+        .map_err(|error| error.to_string())?;
+    Ok(guard)
+}
@@ -3,19 +3,67 @@
 extern crate windows_service;
 
 mod api;
+mod cli;
 mod command;
+mod config;
+mod health;
+mod logging;
+mod lti;
+mod metrics;
 mod server;
 mod state;
+mod webhook;
 
 #[cfg(target_os = "windows")]
 mod windows;
 
 #[cfg(target_os = "windows")]
 pub fn main() -> Result<(), windows_service::Error> {
+    if generate() {
+        return Ok(());
+    }
     windows::main()
 }
 
 #[cfg(not(target_os = "windows"))]
 pub fn main() {
-    crate::server::serve(None);
+    if generate() {
+        return;
+    }
+    let mut args = std::env::args().skip(1).peekable();
+    let check = args.next_if(|x| x == "check-config").is_some();
+    match cli::Options::parse(args) {
+        Ok(options) if check => std::process::exit(if config::check(&options) { 0 } else { 1 }),
+        Ok(options) => {
+            if !crate::server::serve(None, options) {
+                std::process::exit(1);
+            }
+        }
+        Err(error) => {
+            eprintln!("{error}\n{}", cli::HELP);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// `--schema` and `--typescript` print the protocol definitions instead of starting the
+/// server, and `--help` the command line options
+fn generate() -> bool {
+    match std::env::args().nth(1).as_deref() {
+        Some("-h" | "--help") => {
+            println!("uso: extensao [check-config] [opções]\n{}", cli::HELP);
+            true
+        }
+        Some("--schema") => {
+            let schema = serde_json::to_string_pretty(&command::json_schema())
+                .expect("the schema is always serializable into json");
+            println!("{schema}");
+            true
+        }
+        Some("--typescript") => {
+            print!("{}", command::typescript());
+            true
+        }
+        _ => false,
+    }
 }
@@ -0,0 +1,120 @@
+//! Prometheus metrics, served as text by `GET /metrics`.
+//!
+//! Counters are updated where things happen, gauges are refreshed every second by
+//! `periodic_routine`, so a scrape may be up to a second behind.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::new();
+    pub static ref ROOMS: IntGauge = register(IntGauge::new("extensao_rooms", "Active rooms"));
+    pub static ref MEMBERS_ONLINE: IntGauge = register(IntGauge::new(
+        "extensao_members_online",
+        "Members with at least one open connection"
+    ));
+    pub static ref CONNECTIONS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("extensao_connections", "Open connections, by transport"),
+        &["transport"]
+    ));
+    pub static ref MESSAGES_IN: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "extensao_messages_in_total",
+            "Commands received from clients, by command"
+        ),
+        &["cmd"]
+    ));
+    pub static ref MESSAGES_OUT: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "extensao_messages_out_total",
+            "Messages sent to clients, by command"
+        ),
+        &["cmd"]
+    ));
+    pub static ref ERRORS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new(
+            "extensao_errors_total",
+            "Commands rejected by the handlers, by error code"
+        ),
+        &["code"]
+    ));
+    pub static ref GAME_DURATION: Histogram = register(Histogram::with_opts(
+        HistogramOpts::new(
+            "extensao_game_duration_seconds",
+            "Time from Start to Finished, including extra time"
+        )
+        .buckets(vec![
+            60.0, 120.0, 300.0, 600.0, 900.0, 1200.0, 1800.0, 3600.0
+        ])
+    ));
+    pub static ref QUEUED_EVENTS: IntGauge = register(IntGauge::new(
+        "extensao_queued_events",
+        "Events waiting in the queues of all connections"
+    ));
+    pub static ref MAX_QUEUE_DEPTH: IntGauge = register(IntGauge::new(
+        "extensao_max_queue_depth",
+        "Events waiting in the fullest connection queue"
+    ));
+    pub static ref SLOW_DISCONNECTS: IntCounter = register(IntCounter::new(
+        "extensao_slow_disconnects_total",
+        "Connections disconnected because their queue was full"
+    ));
+}
+
+/// registers every metric, the statics are lazy and a metric only shows up in
+/// `/metrics` once registered, so this is called before the server starts listening
+pub fn register_all() {
+    lazy_static::initialize(&ROOMS);
+    lazy_static::initialize(&MEMBERS_ONLINE);
+    lazy_static::initialize(&MESSAGES_IN);
+    lazy_static::initialize(&MESSAGES_OUT);
+    lazy_static::initialize(&ERRORS);
+    lazy_static::initialize(&GAME_DURATION);
+    lazy_static::initialize(&QUEUED_EVENTS);
+    lazy_static::initialize(&MAX_QUEUE_DEPTH);
+    lazy_static::initialize(&SLOW_DISCONNECTS);
+    // the transports are known, so their series start at zero too
+    for transport in ["websocket", "sse"] {
+        CONNECTIONS.with_label_values(&[transport]);
+    }
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: prometheus::Result<T>) -> T {
+    let metric = metric.expect("metric options are valid");
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric names are unique");
+    metric
+}
+
+/// every metric, in the prometheus text format
+pub fn gather() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&REGISTRY.gather(), &mut buffer)
+        .expect("the text encoder does not fail on valid metrics");
+    String::from_utf8(buffer).expect("the text format is utf-8")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn metrics_are_exported_before_being_used() {
+        super::register_all();
+        let metrics = super::gather();
+        for name in [
+            "extensao_rooms",
+            "extensao_members_online",
+            "extensao_connections{transport=\"websocket\"}",
+            "extensao_game_duration_seconds_count",
+            "extensao_queued_events",
+            "extensao_max_queue_depth",
+            "extensao_slow_disconnects_total",
+        ] {
+            assert!(metrics.contains(name), "{name} is missing");
+        }
+    }
+}
@@ -1,35 +1,19 @@
-use std::{io::ErrorKind, time::Duration};
+use futures::FutureExt;
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    pin::Pin,
+    time::Duration,
+};
 use tokio::time::interval;
 use warp::{reply::Reply, Filter};
 
-const CONFIG_FILE: &str = "extensao.json";
-
-#[derive(serde::Serialize, serde::Deserialize)]
-struct Config {
-    ip: String,
-    port: u16,
-    domain: String,
-    base: String,
-    tls: bool,
-    cert: String,
-    key: String,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            ip: "0.0.0.0".to_owned(),
-            port: 80,
-            domain: "127.0.0.1".to_owned(),
-            base: "/".to_owned(),
-            tls: false,
-            cert: "tls/cert.pem".to_owned(),
-            key: "tls/key.rsa".to_owned(),
-        }
-    }
-}
+use crate::cli::Options;
+use crate::command::MasterCommand;
+use crate::config::Config;
 
-pub fn serve(shutdown: Option<tokio::sync::oneshot::Receiver<()>>) {
+/// `false` if the server could not start, like when the config file has errors
+pub fn serve(shutdown: Option<tokio::sync::oneshot::Receiver<()>>, options: Options) -> bool {
     let api_create = warp::post()
         .and(warp::path("sala"))
         .and(warp::path::end())
@@ -45,12 +29,14 @@ pub fn serve(shutdown: Option<tokio::sync::oneshot::Receiver<()>>) {
         .and(warp::path("sala"))
         .and(warp::path::param::<String>())
         .and(warp::path::end())
+        .and(warp::cookie::optional::<String>("device"))
         .map(crate::api::api_join);
 
     let api_join_redirect = warp::get()
         .and(warp::path("entrar"))
         .and(warp::path::param::<String>())
         .and(warp::path::end())
+        .and(warp::cookie::optional::<String>("device"))
         .map(crate::api::api_join_redirect);
 
     let api_connect = warp::ws()
@@ -65,18 +51,221 @@ pub fn serve(shutdown: Option<tokio::sync::oneshot::Receiver<()>>) {
             }
         });
 
+    let api_events = warp::get()
+        .and(warp::path("sala"))
+        .and(warp::path("eventos"))
+        .and(warp::path::end())
+        .and(warp::cookie::<String>("session"))
+        .and(warp::query::<crate::api::EventsQuery>())
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .map(|session, query, last_event_id| {
+            if let Some((roomid, sckid)) = parse_session(session) {
+                crate::api::api_events(roomid, sckid, query, last_event_id)
+            } else {
+                warp::http::StatusCode::BAD_REQUEST.into_response()
+            }
+        });
+
+    let api_command = warp::post()
+        .and(warp::path("sala"))
+        .and(warp::path("comando"))
+        .and(warp::path::end())
+        .and(warp::cookie::<String>("session"))
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::bytes())
+        .map(|session, body| {
+            if let Some((roomid, sckid)) = parse_session(session) {
+                crate::api::api_command(roomid, sckid, body)
+            } else {
+                warp::http::StatusCode::BAD_REQUEST.into_response()
+            }
+        });
+
+    // REST api, authenticated with the tokens in the config file
+    let rest_room = warp::path("api")
+        .and(warp::path("sala"))
+        .and(warp::path::param::<String>());
+    let authorization = warp::header::optional::<String>("authorization");
+
+    let api_room = warp::get()
+        .and(rest_room)
+        .and(warp::path::end())
+        .and(authorization)
+        .map(crate::api::api_room);
+
+    let api_room_update = warp::patch()
+        .and(rest_room)
+        .and(warp::path::end())
+        .and(authorization)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .map(crate::api::api_room_update);
+
+    let api_room_close = warp::delete()
+        .and(rest_room)
+        .and(warp::path::end())
+        .and(authorization)
+        .map(|room, authorization| {
+            crate::api::api_room_command(room, authorization, MasterCommand::CloseRoom)
+        });
+
+    let api_room_members = warp::get()
+        .and(rest_room)
+        .and(warp::path("membros"))
+        .and(warp::path::end())
+        .and(authorization)
+        .map(crate::api::api_room_members);
+
+    let api_room_answers = warp::get()
+        .and(rest_room)
+        .and(warp::path("respostas"))
+        .and(warp::path::end())
+        .and(authorization)
+        .map(crate::api::api_room_answers);
+
+    let api_room_start = warp::post()
+        .and(rest_room)
+        .and(warp::path("iniciar"))
+        .and(warp::path::end())
+        .and(authorization)
+        .map(|room, authorization| {
+            crate::api::api_room_command(room, authorization, MasterCommand::Start)
+        });
+
+    let api_room_finish = warp::post()
+        .and(rest_room)
+        .and(warp::path("terminar"))
+        .and(warp::path::end())
+        .and(authorization)
+        .map(|room, authorization| {
+            crate::api::api_room_command(room, authorization, MasterCommand::Finish)
+        });
+
+    let api_webhooks = warp::get()
+        .and(warp::path("api"))
+        .and(warp::path("webhooks"))
+        .and(warp::path::end())
+        .and(authorization)
+        .map(crate::api::api_webhooks);
+
+    let rest = api_room
+        .or(api_room_update)
+        .or(api_room_close)
+        .or(api_room_members)
+        .or(api_room_answers)
+        .or(api_room_start)
+        .or(api_room_finish)
+        .or(api_webhooks);
+
+    // admin console, authenticated with the admin tokens in the config file
+    let admin_page = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path::end())
+        .map(crate::api::api_admin_page);
+
+    let admin_rooms = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("salas"))
+        .and(warp::path::end())
+        .and(authorization)
+        .map(crate::api::api_admin_rooms);
+
+    let admin_close = warp::delete()
+        .and(warp::path("admin"))
+        .and(warp::path("sala"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(authorization)
+        .map(crate::api::api_admin_close);
+
+    let admin_announce = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("anuncio"))
+        .and(warp::path::end())
+        .and(authorization)
+        .and(warp::body::content_length_limit(16 * 1024))
+        .and(warp::body::json())
+        .map(crate::api::api_admin_announce);
+
+    let admin_status = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("servidor"))
+        .and(warp::path::end())
+        .and(authorization)
+        .map(crate::api::api_admin_status);
+
+    let admin = admin_page
+        .or(admin_rooms)
+        .or(admin_close)
+        .or(admin_announce)
+        .or(admin_status);
+
+    // LTI 1.3, the platform may send the login as a GET or as a form POST
+    let lti_login = warp::path("lti")
+        .and(warp::path("login"))
+        .and(warp::path::end())
+        .and(
+            warp::get()
+                .and(warp::query::<crate::lti::LoginRequest>())
+                .or(warp::post()
+                    .and(warp::body::content_length_limit(16 * 1024))
+                    .and(warp::body::form::<crate::lti::LoginRequest>()))
+                .unify(),
+        )
+        .map(crate::api::api_lti_login);
+
+    let lti_launch = warp::post()
+        .and(warp::path("lti"))
+        .and(warp::path("entrar"))
+        .and(warp::path::end())
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::form::<crate::lti::LaunchRequest>())
+        .then(crate::api::api_lti_launch);
+
+    let lti = lti_login.or(lti_launch);
+
+    let api_schema = warp::get()
+        .and(warp::path("protocol.schema.json"))
+        .and(warp::path::end())
+        .map(|| warp::reply::json(&crate::command::json_schema()));
+
+    let api_metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .map(crate::api::api_metrics);
+
+    let api_healthz = warp::get()
+        .and(warp::path("healthz"))
+        .and(warp::path::end())
+        .map(crate::api::api_healthz);
+
+    let api_readyz = warp::get()
+        .and(warp::path("readyz"))
+        .and(warp::path::end())
+        .map(crate::api::api_readyz);
+
     let api_qrcode = warp::get()
         .and(warp::path("qrcode"))
         .and(warp::path::param::<String>())
         .and(warp::path::end())
         .map(crate::api::api_qrcode);
 
+    // before api_join, which would take "comando" for a room code
     let apis = api_create
         .or(api_leave)
+        .or(api_command)
         .or(api_join)
         .or(api_join_redirect)
         .or(api_connect)
-        .or(api_qrcode);
+        .or(api_events)
+        .or(api_schema)
+        .or(api_metrics)
+        .or(api_healthz)
+        .or(api_readyz)
+        .or(api_qrcode)
+        .or(rest)
+        .or(admin)
+        .or(lti);
 
     #[cfg(not(debug_assertions))] // load assets from executable
     let files = static_dir::static_dir!("static");
@@ -95,70 +284,106 @@ pub fn serve(shutdown: Option<tokio::sync::oneshot::Receiver<()>>) {
 
     let routes = files.or(apis).map(disable_caching);
 
-    // cd into folder of executable, for reading the correct config file
-    #[cfg(not(debug_assertions))]
-    std::env::set_current_dir(
-        std::env::current_exe()
-            .expect("current_exe")
-            .parent()
-            .expect("parent"),
-    )
-    .expect("set_current_dir");
-
-    let config = match std::fs::metadata(CONFIG_FILE) {
-        Ok(metadata) => metadata.is_file(),
-        Err(error) if error.kind() == ErrorKind::NotFound => {
-            let default_config_file = serde_json::to_string_pretty(&Config::default()).unwrap();
-            std::fs::write(CONFIG_FILE, default_config_file).is_ok()
+    let config_file = match crate::config::locate(&options) {
+        Ok(config_file) => config_file,
+        Err(diagnostic) => {
+            eprintln!("{diagnostic}");
+            return false;
         }
-        Err(_) => false,
     };
+    let (config, diagnostics) = crate::config::load(&config_file, &options, !options.no_write);
 
-    if !config {
-        println!(
-            "[!] ERROR: default \"{}\" file was not found and could not be created",
-            CONFIG_FILE
-        );
-        return;
+    let _log_guard = match crate::logging::init(&config.logging) {
+        Ok(guard) => guard,
+        Err(error) => {
+            eprintln!("[!] ERROR: {error}");
+            for diagnostic in &diagnostics {
+                eprintln!("{}:{diagnostic}", config_file.display());
+            }
+            return false;
+        }
+    };
+
+    for variable in &options.ignored {
+        tracing::warn!("{variable} is not an option, the environment variable was ignored");
     }
 
-    let config = match std::fs::read_to_string(CONFIG_FILE) {
-        Ok(config) => config,
-        Err(_) => {
-            println!("[!] ERROR: could not read config file");
-            return;
+    let mut valid = true;
+    for diagnostic in &diagnostics {
+        if diagnostic.warning {
+            tracing::warn!("{}:{diagnostic}", config_file.display());
+        } else {
+            tracing::error!("{}:{diagnostic}", config_file.display());
+            valid = false;
         }
-    };
+    }
+    if !valid {
+        return false;
+    }
+
+    let listeners = config.listeners();
 
     let Config {
-        ip,
-        port,
+        ip: _,
+        port: _,
         domain,
         base,
-        tls,
+        tls: _,
         cert,
         key,
-    } = match serde_json::from_str(&config) {
-        Ok(config) => config,
-        Err(error) => {
-            println!("[!] ERROR: config file is not a valid json config file\n{error:#?}");
-            return;
-        }
-    };
-
-    let scheme = if tls { "https" } else { "http" };
+        listen: _,
+        names,
+        heartbeat,
+        api_tokens,
+        admin_tokens,
+        webhooks,
+        lti,
+        positions_interval_ms,
+        logging: _,
+    } = config;
+
+    // the urls in the qr codes and sent to the LTI platform use the first listener
+    // with tls, or the first one
+    let public = listeners.iter().find(|x| x.tls).unwrap_or(&listeners[0]);
+    let scheme = if public.tls { "https" } else { "http" };
+    let port = public.port;
 
     let qrcode_url_prefix = format!("{scheme}://{domain}:{port}{base}entrar/");
+    let lti_launch_url = format!("{scheme}://{domain}:{port}{base}lti/entrar");
+
+    let _ = crate::api::URL_BASE.set(base);
+    let _ = crate::api::QRCODE_URL_PREFIX.set(qrcode_url_prefix);
+    let _ = crate::api::API_TOKENS.set(api_tokens);
+    let _ = crate::api::ADMIN_TOKENS.set(admin_tokens);
+    let _ = crate::api::STARTED.set(std::time::Instant::now());
+
+    crate::state::set_name_rules(names);
+    crate::state::set_heartbeat(heartbeat);
+    crate::webhook::set_webhooks(webhooks);
+    crate::lti::set_lti(lti, lti_launch_url);
+    crate::metrics::register_all();
+
+    let mut addrs = Vec::new();
+    for listener in &listeners {
+        match listen_addrs(&listener.ip, listener.port) {
+            Ok(resolved) => addrs.extend(resolved.into_iter().map(|addr| (addr, listener.tls))),
+            Err(error) => {
+                tracing::error!("{error}");
+                return false;
+            }
+        }
+    }
 
-    unsafe {
-        crate::api::URL_BASE = base;
-        crate::api::QRCODE_URL_PREFIX = qrcode_url_prefix;
+    if let Err(error) = check_bind(addrs.iter().map(|(addr, _)| *addr)) {
+        tracing::error!("{error}");
+        return false;
     }
 
-    let Some(ip) = parse_ip(&ip) else {
-        println!("[!] ERROR: ip \"{}\" is not valid", ip);
-        return;
-    };
+    if listeners.iter().any(|x| x.tls) {
+        crate::health::config_loaded(Some((cert.clone(), key.clone())));
+    } else {
+        crate::health::config_loaded(None);
+    }
 
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -167,6 +392,15 @@ pub fn serve(shutdown: Option<tokio::sync::oneshot::Receiver<()>>) {
 
     let _enter = rt.enter();
 
+    tokio::spawn(async move {
+        let mut flush_task = interval(Duration::from_millis(positions_interval_ms.max(1)));
+        flush_task.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            flush_task.tick().await;
+            crate::state::flush_positions();
+        }
+    });
+
     // Run a function every second
     tokio::spawn(async move {
         let mut tick = 0;
@@ -174,65 +408,108 @@ pub fn serve(shutdown: Option<tokio::sync::oneshot::Receiver<()>>) {
         tick_task.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
             crate::state::periodic_routine(tick);
+            crate::health::ticked();
             tick = tick.wrapping_add(1);
             tick_task.tick().await;
         }
     });
 
-    let server = warp::serve(routes);
-
     let signal = async move {
-        if ip == [0, 0, 0, 0] {
-            println!("[*] Projeto de Extensao *:{}", port);
-        } else {
-            println!(
-                "[*] Projeto de Extensao {}.{}.{}.{}:{}",
-                ip[0], ip[1], ip[2], ip[3], port
-            );
-        }
         if let Some(shutdown) = shutdown {
             shutdown
                 .await
                 .expect("The shutdown oneshot chanel's sender must not be dropped");
-            println!("[*] Stopping service");
+            tracing::info!("stopping service");
         } else {
-            if let Err(_) = tokio::signal::ctrl_c().await {
-                println!("[!] Failed to detect CTRL-C");
+            if tokio::signal::ctrl_c().await.is_err() {
+                tracing::error!("failed to detect CTRL-C");
                 // the line below never returns
                 let () = std::future::pending().await;
             }
-            println!("[*] CTRL-C detected");
+            tracing::info!("CTRL-C detected");
         }
-    };
+        crate::health::shutting_down();
+    }
+    .shared();
 
-    if tls {
-        rt.block_on(
-            server
+    let servers = addrs.into_iter().map(|(addr, tls)| {
+        let server = warp::serve(routes.clone());
+        let signal = signal.clone();
+        let (addr, server): (_, Pin<Box<dyn Future<Output = ()>>>) = if tls {
+            let (addr, server) = server
                 .tls()
                 .cert_path(&cert)
                 .key_path(&key)
-                .bind_with_graceful_shutdown((ip, port), signal)
-                .1,
-        );
-    } else {
-        rt.block_on(server.bind_with_graceful_shutdown((ip, port), signal).1);
-    }
+                .bind_with_graceful_shutdown(addr, signal);
+            (addr, Box::pin(server))
+        } else {
+            let (addr, server) = server.bind_with_graceful_shutdown(addr, signal);
+            (addr, Box::pin(server))
+        };
+        let scheme = if tls { "https" } else { "http" };
+        tracing::info!("Projeto de Extensao {scheme}://{addr}");
+        server
+    });
+    let servers: Vec<_> = servers.collect();
+
+    rt.block_on(futures::future::join_all(servers));
+    true
 }
 
-fn parse_ip(ip: &str) -> Option<[u8; 4]> {
+/// the addresses of `ip`, which may be an IPv4 or IPv6 address or a hostname
+pub fn listen_addrs(ip: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
     if ip.is_empty() {
-        return Some([0, 0, 0, 0]);
+        return Ok(every_interface(port));
+    }
+    // IPv6 addresses may be written in brackets, like in urls
+    let unbracketed = ip
+        .strip_prefix('[')
+        .and_then(|x| x.strip_suffix(']'))
+        .unwrap_or(ip);
+    if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    let mut addrs: Vec<SocketAddr> = (ip, port)
+        .to_socket_addrs()
+        .map_err(|error| format!("ip \"{ip}\" is not valid: {error}"))?
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    if addrs.is_empty() {
+        return Err(format!("hostname \"{ip}\" has no addresses"));
+    }
+    Ok(addrs)
+}
+
+/// IPv4 and IPv6 on every interface, an IPv6 socket also accepts IPv4 by default on
+/// Linux but not on Windows, so the IPv4 socket is only added if it does not conflict
+fn every_interface(port: u16) -> Vec<SocketAddr> {
+    let v4 = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let v6 = SocketAddr::from((Ipv6Addr::UNSPECIFIED, port));
+    let Ok(probe) = std::net::TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)) else {
+        // IPv6 is disabled on this machine
+        return vec![v4];
+    };
+    let dual_stack = probe.local_addr().is_ok_and(|addr| {
+        std::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, addr.port())).is_err()
+    });
+    if dual_stack {
+        vec![v6]
+    } else {
+        vec![v6, v4]
     }
-    let parts: Vec<&str> = ip.splitn(5, '.').collect();
-    if parts.len() != 4 {
-        return None;
+}
+
+/// warp panics if it cannot bind, so every address is bound once before, all at the
+/// same time so that conflicts between the listeners are also found
+fn check_bind(addrs: impl Iterator<Item = SocketAddr>) -> Result<(), String> {
+    let mut bound = Vec::new();
+    for addr in addrs {
+        let listener = std::net::TcpListener::bind(addr)
+            .map_err(|error| format!("could not listen on {addr}: {error}"))?;
+        bound.push(listener);
     }
-    Some([
-        parts[0].parse().ok()?,
-        parts[1].parse().ok()?,
-        parts[2].parse().ok()?,
-        parts[3].parse().ok()?,
-    ])
+    Ok(())
 }
 
 fn parse_session(mut session: String) -> Option<(String, u32)> {
@@ -250,6 +527,5 @@ fn disable_caching(reply: impl Reply) -> impl Reply {
         "no-cache, no-store, must-revalidate",
     );
     let reply = with_header(reply, "Pragma", "no-cache");
-    let reply = with_header(reply, "Expires", "0");
-    reply
+    with_header(reply, "Expires", "0")
 }
@@ -1,26 +1,100 @@
 use rand::Rng;
 use std::{
-    cell::RefCell,
-    collections::BTreeMap,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc::Sender;
-use warp::filters::ws::Message;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::command::{
+    self, Answer, Encoding, ErrorCode, Event, KickedMember, LateJoin, Position, Presence, Request,
+    ServerCommand,
+};
+use crate::metrics;
+use crate::webhook::{self, WebhookEvent};
+
+pub type EventSender = Sender<Arc<Event>>;
+pub type WeakEventSender = tokio::sync::mpsc::WeakSender<Arc<Event>>;
+pub type EventReceiver = tokio::sync::mpsc::Receiver<Arc<Event>>;
 
-use crate::command::{self, Answer, ServerCommand};
+/// how many events each room remembers for resyncing reconnecting clients, positions
+/// are not numbered, so they do not push the rest of the events out of it
+const EVENT_LOG_SIZE: usize = 512;
+/// how many events may wait in the queue of a connection before it is considered too
+/// slow and disconnected, big enough for a full replay of the event log
+const CONNECTION_QUEUE_SIZE: usize = 2 * EVENT_LOG_SIZE;
+/// every how many seconds the queue metrics are printed, they are exported every second
+const QUEUE_METRICS_INTERVAL: usize = 60;
+/// how many acknowledgements each room remembers for repeated request ids
+const ACK_LOG_SIZE: usize = 256;
+/// every how many seconds the master receives a full `AnswersChanged`, if anything changed
+const ANSWERS_SNAPSHOT_INTERVAL: usize = 10;
 
 // safe because this app is single threaded
 unsafe impl Sync for Rooms {}
 
 struct Rooms {
     rooms: RefCell<BTreeMap<String, Room>>,
+    names: RefCell<NameRules>,
+    heartbeat: Cell<Heartbeat>,
+}
+
+/// rules applied to every member name, configured by the `names` field of the config file
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct NameRules {
+    /// maximum length in characters, after trimming and normalization
+    pub max_length: usize,
+    /// apply unicode NFKC normalization, so look-alike characters compare equal
+    pub normalize: bool,
+    /// append a number to names already used by another member of the room, needs a
+    /// `max_length` of at least `MIN_DISAMBIGUATED_LENGTH`
+    pub disambiguate: bool,
+    /// names containing any of these words are rejected, compared case-insensitively
+    pub blocklist: Vec<String>,
+}
+
+/// room for one character of the name and the number, like `A 2`
+pub const MIN_DISAMBIGUATED_LENGTH: usize = 3;
+
+impl Default for NameRules {
+    fn default() -> Self {
+        Self {
+            max_length: 18,
+            normalize: true,
+            disambiguate: true,
+            blocklist: Vec::new(),
+        }
+    }
+}
+
+/// how connections are kept alive, configured by the `heartbeat` field of the config file
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Heartbeat {
+    /// every how many seconds a ping is sent to each connection
+    pub ping_interval_secs: u64,
+    /// a connection that sent nothing, not even a pong, for this long is closed
+    pub ping_timeout_secs: u64,
+    /// how long a member without connections stays away before being removed
+    pub away_grace_secs: u64,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            ping_interval_secs: 10,
+            ping_timeout_secs: 30,
+            away_grace_secs: 30,
+        }
+    }
 }
 
 struct Room {
+    /// the room code
+    code: String,
     last_interaction: Instant,
     game: Game,
     event_time: u32,
@@ -30,18 +104,46 @@ struct Room {
     group_false_color: String,
     group_true_name: String,
     group_true_color: String,
+    late_join: LateJoin,
+    /// when set, members can no longer change their own names
+    names_locked: bool,
+    /// device ids that may not join this room
+    banned: BTreeSet<String>,
     /// skcid zero connections
     conns: Connections,
+    /// sequence number of the last event sent in this room
+    seq: u64,
+    /// the last `EVENT_LOG_SIZE` events, and who they were sent to
+    log: VecDeque<(Audience, Arc<Event>)>,
+    /// the last `ACK_LOG_SIZE` acknowledgements, by request
+    acks: Acks,
+    /// answers changed since the master last received a full `AnswersChanged`
+    answers_dirty: bool,
+    /// members that moved since the last `PositionsChanged`
+    moved: BTreeSet<u32>,
 }
 
 enum Game {
     Idle,
-    Started { start: Instant, extra: u32 },
-    Ended(Message),
+    Started {
+        start: Instant,
+        extra: u32,
+    },
+    /// holds the `Finished` command, for sending it again on reconnects
+    Ended(ServerCommand),
+}
+
+#[derive(Clone, Copy)]
+enum Audience {
+    All,
+    Master,
+    Member(u32),
 }
 
 struct Member {
     sckid: u32,
+    /// the device cookie of the browser that joined, used for bans and rejoins
+    device: String,
     online: usize,
     name: String,
     group: bool,
@@ -49,18 +151,75 @@ struct Member {
     conns: Connections,
     answers: BTreeMap<u32, u32>,
     kicked: bool,
+    banned: bool,
+    kicked_at: String,
+    /// private note, only the master can see it
+    note: String,
+    /// joined during a game and is waiting for the master's approval
+    pending: bool,
+    /// lost its last connection at this moment, and is removed if it does not reconnect
+    away_since: Option<Instant>,
     x: f32,
     y: f32,
 }
 
+/// one queue per connection, events are queued synchronously so their order is kept
 struct Connections {
-    senders: Vec<Arc<(Sender<Message>, AtomicBool)>>,
+    senders: Vec<EventSender>,
+}
+
+struct Acks {
+    acks: VecDeque<(AckKey, ServerCommand)>,
+}
+
+/// a request is only the same if everything about it is, an `id` repeated with
+/// another command is a new command
+#[derive(PartialEq)]
+struct AckKey {
+    sckid: u32,
+    session: Option<String>,
+    id: u64,
+    /// the command, as json
+    command: String,
+}
+
+impl AckKey {
+    fn new(sckid: u32, session: Option<String>, id: u64, command: &impl serde::Serialize) -> Self {
+        Self {
+            sckid,
+            session,
+            id,
+            command: serde_json::to_string(command).unwrap_or_default(),
+        }
+    }
+}
+
+impl Acks {
+    fn new() -> Self {
+        Self {
+            acks: VecDeque::new(),
+        }
+    }
+    fn get(&self, key: &AckKey) -> Option<ServerCommand> {
+        self.acks
+            .iter()
+            .find(|(ack_key, _)| ack_key == key)
+            .map(|(_, ack)| ack.clone())
+    }
+    fn push(&mut self, key: AckKey, ack: ServerCommand) {
+        if self.acks.len() == ACK_LOG_SIZE {
+            self.acks.pop_front();
+        }
+        self.acks.push_back((key, ack));
+    }
 }
 
 lazy_static::lazy_static! {
     static ref STATE: Rooms = {
         Rooms {
-            rooms: RefCell::new(BTreeMap::new())
+            rooms: RefCell::new(BTreeMap::new()),
+            names: RefCell::new(NameRules::default()),
+            heartbeat: Cell::new(Heartbeat::default()),
         }
     };
 }
@@ -86,15 +245,110 @@ fn random_room_code() -> String {
     ]))
 }
 
+pub fn set_name_rules(rules: NameRules) {
+    *STATE.names.borrow_mut() = rules;
+}
+
+pub fn set_heartbeat(heartbeat: Heartbeat) {
+    STATE.heartbeat.set(heartbeat);
+}
+
+pub fn heartbeat() -> Heartbeat {
+    STATE.heartbeat.get()
+}
+
+impl NameRules {
+    /// trims, normalizes and checks a name requested for a member of `room`
+    fn apply(&self, room: &Room, sckid: u32, name: &str) -> Result<String, Error> {
+        let name: String = if self.normalize {
+            name.nfkc().collect()
+        } else {
+            name.to_owned()
+        };
+        // collapse runs of whitespace and drop control characters
+        let name = name
+            .split_whitespace()
+            .map(|word| word.chars().filter(|c| !c.is_control()).collect::<String>())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if name.is_empty() {
+            return Err(Error::NameEmpty);
+        }
+        if name.chars().count() > self.max_length {
+            return Err(Error::NameTooLong {
+                max_length: self.max_length,
+            });
+        }
+        let lowercase = name.to_lowercase();
+        let words: Vec<&str> = lowercase
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        for blocked in &self.blocklist {
+            let blocked = blocked.nfkc().collect::<String>().to_lowercase();
+            if words.contains(&blocked.as_str()) {
+                return Err(Error::NameBlocked);
+            }
+        }
+        if !self.disambiguate {
+            return Ok(name);
+        }
+        let taken = |candidate: &str| {
+            room.members.iter().any(|x| {
+                x.sckid != sckid && !x.kicked && x.name.to_lowercase() == candidate.to_lowercase()
+            })
+        };
+        if !taken(&name) {
+            return Ok(name);
+        }
+        for number in 2.. {
+            let suffix = format!(" {number}");
+            let room_for_name = self.max_length.saturating_sub(suffix.chars().count());
+            let base: String = name.chars().take(room_for_name).collect();
+            // without room for the name only the number is left
+            let candidate = format!("{}{suffix}", base.trim_end())
+                .trim_start()
+                .to_owned();
+            if candidate.chars().count() > self.max_length {
+                break;
+            }
+            if !taken(&candidate) {
+                return Ok(candidate);
+            }
+        }
+        // every number that fits is taken, the name is repeated instead
+        Ok(name)
+    }
+}
+
+impl Audience {
+    fn includes(self, sckid: u32) -> bool {
+        match self {
+            Audience::All => true,
+            Audience::Master => sckid == 0,
+            Audience::Member(member) => member == sckid,
+        }
+    }
+}
+
 impl Game {
     fn is_started(&self) -> bool {
         matches!(self, Self::Started { .. })
     }
+    fn status(&self) -> command::GameStatus {
+        match self {
+            Self::Idle => command::GameStatus::Idle,
+            Self::Started { .. } => command::GameStatus::Started,
+            Self::Ended(_) => command::GameStatus::Ended,
+        }
+    }
 }
 
 impl Room {
-    fn new() -> Self {
+    fn new(code: String) -> Self {
         Self {
+            code,
             last_interaction: Instant::now(),
             game: Game::Idle,
             event_time: 300,
@@ -104,21 +358,171 @@ impl Room {
             group_false_color: "170,68,68".to_owned(),
             group_true_name: "Grupo Azul".to_owned(),
             group_true_color: "68,68,170".to_owned(),
+            late_join: LateJoin::Deny,
+            names_locked: false,
+            banned: BTreeSet::new(),
             members: Vec::new(),
+            seq: 0,
+            log: VecDeque::new(),
+            acks: Acks::new(),
+            answers_dirty: false,
+            moved: BTreeSet::new(),
+        }
+    }
+    /// numbers the command, records it in the event log and sends it to the audience
+    fn send(&mut self, audience: Audience, command: ServerCommand) {
+        self.seq += 1;
+        let event = Event::new(Some(self.seq), command);
+        if self.log.len() == EVENT_LOG_SIZE {
+            self.log.pop_front();
+        }
+        self.log.push_back((audience, Arc::clone(&event)));
+        match audience {
+            Audience::All => {
+                self.conns.send(&event);
+                for member in &mut self.members {
+                    member.conns.send(&event);
+                }
+            }
+            Audience::Master => self.conns.send(&event),
+            Audience::Member(sckid) => {
+                if let Ok(member) = self.member_mut(sckid) {
+                    member.conns.send(&event);
+                }
+            }
         }
     }
-    fn send_all(&mut self, message: &Message) {
-        self.send_master(message);
-        self.send_members(message);
+    fn send_all(&mut self, command: ServerCommand) {
+        self.send(Audience::All, command);
     }
-    fn send_master(&mut self, message: &Message) {
-        self.conns.send(message);
+    fn send_master(&mut self, command: ServerCommand) {
+        self.send(Audience::Master, command);
     }
-    fn send_members(&mut self, message: &Message) {
+    fn send_member(&mut self, sckid: u32, command: ServerCommand) {
+        self.send(Audience::Member(sckid), command);
+    }
+    /// sends the command to everyone without numbering it, for events that are
+    /// outdated by the next one, like positions, and so are never replayed
+    fn send_transient(&mut self, command: ServerCommand) {
+        let event = Event::new(None, command);
+        self.conns.send(&event);
         for member in &mut self.members {
-            member.send(message);
+            member.conns.send(&event);
+        }
+    }
+    /// the current position of every member shown, for clients that missed some
+    /// `PositionsChanged`
+    fn positions(&self) -> ServerCommand {
+        ServerCommand::PositionsChanged {
+            positions: self
+                .members
+                .iter()
+                .filter(|x| x.is_visible())
+                .map(|x| Position {
+                    sckid: x.sckid,
+                    x: x.x,
+                    y: x.y,
+                })
+                .collect(),
         }
     }
+    /// everything a new connection of sckid needs to know about the room
+    fn snapshot(&self, sckid: u32) -> Vec<ServerCommand> {
+        let mut messages: Vec<ServerCommand> = Vec::with_capacity(5);
+
+        messages.push(self.to_message());
+        messages.push(ServerCommand::MembersChanged {
+            members: self.get_group_members(),
+        });
+        for member in &self.members {
+            if member.is_visible() {
+                messages.push(ServerCommand::MemberUpdated {
+                    member: member.into(),
+                });
+            }
+        }
+
+        if sckid == 0 {
+            for member in &self.members {
+                if !member.note.is_empty() {
+                    messages.push(ServerCommand::MemberNote {
+                        sckid: member.sckid,
+                        note: member.note.clone(),
+                    });
+                }
+            }
+            if self.members.iter().any(|x| x.kicked) {
+                messages.push(ServerCommand::KickedChanged {
+                    kicked: self.get_kicked(),
+                });
+            }
+            if self.members.iter().any(|x| x.pending && !x.kicked) {
+                messages.push(ServerCommand::JoinRequestsChanged {
+                    members: self.get_join_requests(),
+                });
+            }
+        }
+
+        let pending = sckid != 0 && self.members[sckid as usize - 1].pending;
+
+        match &self.game {
+            Game::Idle => {}
+            Game::Started { .. } if pending => {}
+            Game::Started { .. } => {
+                let answers = if sckid == 0 {
+                    self.get_answers()
+                } else {
+                    let member = (&self.members[sckid as usize - 1]).into();
+                    let answers = self.members[sckid as usize - 1]
+                        .answers
+                        .iter()
+                        .map(|(&question, &answer)| Answer { question, answer })
+                        .collect();
+                    vec![crate::command::MemberAnswers { member, answers }]
+                };
+                messages.push(ServerCommand::AnswersChanged { answers });
+                messages.push(ServerCommand::Started {
+                    remaining: self.remaining(),
+                });
+            }
+            Game::Ended(finished) => messages.push(finished.clone()),
+        }
+        messages
+    }
+    /// the events sckid missed since `last_seq`, or `None` if they are no longer in the log,
+    /// followed by the current positions, which are not in the log
+    ///
+    /// a missed `Started` is sent with the time remaining now instead of the full game
+    /// time, which already counts the `ExtraTime`s after it, so those are left out
+    fn replay(&self, sckid: u32, last_seq: u64) -> Option<Vec<Arc<Event>>> {
+        if last_seq > self.seq {
+            return None;
+        }
+        if let Some((_, oldest)) = self.log.front() {
+            if oldest.seq? > last_seq + 1 {
+                return None;
+            }
+        } else if last_seq != self.seq {
+            return None;
+        }
+        let mut started = false;
+        let mut events: Vec<_> = self
+            .log
+            .iter()
+            .filter(|(audience, event)| event.seq > Some(last_seq) && audience.includes(sckid))
+            .filter_map(|(_, event)| match event.command {
+                ServerCommand::Started { .. } => {
+                    started = true;
+                    let remaining = self.remaining();
+                    Some(Event::new(event.seq, ServerCommand::Started { remaining }))
+                }
+                ServerCommand::ExtraTime { .. } if started => None,
+                _ => Some(Arc::clone(event)),
+            })
+            .collect();
+        events.push(Event::new(None, self.positions()));
+        Some(events)
+    }
     fn interacted(&mut self) -> &mut Self {
         self.last_interaction = Instant::now();
         self
@@ -132,7 +536,7 @@ impl Room {
     fn get_answers(&self) -> Vec<command::MemberAnswers> {
         self.members
             .iter()
-            .filter(|x| x.online != 0 && !x.kicked)
+            .filter(|x| x.is_visible())
             .map(|x| command::MemberAnswers {
                 member: x.into(),
                 answers: x
@@ -147,12 +551,12 @@ impl Room {
     fn get_group_members(&self) -> Vec<command::Member> {
         self.members
             .iter()
-            .filter(|x| x.online != 0 && !x.kicked)
+            .filter(|x| x.is_visible())
             .map(|x| x.into())
             .collect()
     }
 
-    fn into_message(&self) -> command::ServerCommand {
+    fn to_message(&self) -> command::ServerCommand {
         command::ServerCommand::RoomChanged {
             game_time: self.event_time,
             question_pool: self.question_pool.clone(),
@@ -160,8 +564,202 @@ impl Room {
             group_false_color: self.group_false_color.clone(),
             group_true_name: self.group_true_name.clone(),
             group_true_color: self.group_true_color.clone(),
+            late_join: self.late_join,
+            names_locked: self.names_locked,
+            max_name_length: STATE.names.borrow().max_length as u32,
+        }
+    }
+
+    fn member_mut(&mut self, sckid: u32) -> Result<&mut Member, Error> {
+        if sckid == 0 {
+            return Err(Error::MemberNotFound);
+        }
+        self.members
+            .get_mut(sckid as usize - 1)
+            .ok_or(Error::MemberNotFound)
+    }
+
+    /// sends the member's new state to everyone, after a change of name or group
+    fn broadcast_member(&mut self, sckid: u32) {
+        let Ok(member) = self.member_mut(sckid) else {
+            return;
+        };
+        if !member.is_visible() {
+            return;
+        }
+        let updated = ServerCommand::MemberUpdated {
+            member: (&*member).into(),
+        };
+        let changed = ServerCommand::MembersChanged {
+            members: self.get_group_members(),
+        };
+        self.send_all(updated);
+        self.send_all(changed);
+    }
+
+    fn remaining(&self) -> u32 {
+        match self.game {
+            Game::Started { start, extra } => Duration::from_secs((self.event_time + extra) as u64)
+                .saturating_sub(start.elapsed())
+                .as_secs() as u32,
+            _ => 0,
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Game::Started { start, extra } = self.game {
+            let elapsed = start.elapsed();
+            metrics::GAME_DURATION.observe(elapsed.as_secs_f64());
+            tracing::info!(
+                room = self.code,
+                elapsed = elapsed.as_secs(),
+                game_time = self.event_time,
+                extra,
+                "game ended"
+            );
+        }
+        let finished = ServerCommand::Finished {
+            member_answers: self.get_answers(),
+            question_pool: self.question_pool.clone(),
+        };
+        webhook::send(WebhookEvent::GameFinished {
+            room: self.code.clone(),
+            question_pool: self.question_pool.clone(),
+            results: self.get_answers(),
+        });
+        crate::lti::game_finished(
+            &self.question_pool,
+            self.members
+                .iter()
+                .filter(|x| x.is_visible())
+                .map(|x| (x.device.as_str(), &x.answers)),
+        );
+        self.send_all(finished.clone());
+        self.game = Game::Ended(finished);
+        // `Finished` already has every answer
+        self.answers_dirty = false;
+        // nobody is late anymore, the next game is open to everyone
+        let mut accepted = false;
+        for member in &mut self.members {
+            accepted |= std::mem::take(&mut member.pending);
+        }
+        if accepted {
+            self.send_all(ServerCommand::MembersChanged {
+                members: self.get_group_members(),
+            });
+            self.send_join_requests();
         }
     }
+
+    fn get_kicked(&self) -> Vec<KickedMember> {
+        self.members
+            .iter()
+            .filter(|x| x.kicked)
+            .map(|x| KickedMember {
+                member: x.into(),
+                banned: x.banned,
+                kicked_at: x.kicked_at.clone(),
+            })
+            .collect()
+    }
+
+    fn get_join_requests(&self) -> Vec<command::Member> {
+        self.members
+            .iter()
+            .filter(|x| x.pending && !x.kicked)
+            .map(|x| x.into())
+            .collect()
+    }
+
+    fn send_join_requests(&mut self) {
+        self.send_master(ServerCommand::JoinRequestsChanged {
+            members: self.get_join_requests(),
+        });
+    }
+
+    /// the answers and name are kept, so that `unkick` can restore the member
+    fn kick(&mut self, sckid: u32, ban: bool) -> Result<(), Error> {
+        {
+            let member = self.member_mut(sckid)?;
+            if member.kicked && (member.banned || !ban) {
+                return Ok(());
+            }
+            member.online = 0;
+            member.away_since = None;
+            self.send_member(sckid, ServerCommand::RoomClosed);
+            let member = self.member_mut(sckid)?;
+            member.conns.close();
+            member.kicked = true;
+            member.banned = ban;
+            member.kicked_at = chrono::Local::now().to_rfc3339();
+            let was_pending = std::mem::take(&mut member.pending);
+            if ban {
+                let device = member.device.clone();
+                self.banned.insert(device);
+            }
+            self.send_all(ServerCommand::MemberRemoved { sckid });
+            self.send_all(ServerCommand::MembersChanged {
+                members: self.get_group_members(),
+            });
+            self.send_master(ServerCommand::KickedChanged {
+                kicked: self.get_kicked(),
+            });
+            if was_pending {
+                self.send_join_requests();
+            }
+        }
+        Ok(())
+    }
+
+    /// lifts a kick or ban, the member gets their answers back once they reconnect
+    fn unkick(&mut self, sckid: u32) -> Result<(), Error> {
+        let member = self.member_mut(sckid)?;
+        if !member.kicked {
+            return Ok(());
+        }
+        member.kicked = false;
+        member.banned = false;
+        member.kicked_at.clear();
+        let device = member.device.clone();
+        self.banned.remove(&device);
+        self.send_master(ServerCommand::KickedChanged {
+            kicked: self.get_kicked(),
+        });
+        Ok(())
+    }
+
+    fn accept(&mut self, sckid: u32) -> Result<(), Error> {
+        let remaining = self.remaining();
+        let started = self.game.is_started();
+        let member = self.member_mut(sckid)?;
+        if !member.pending || member.kicked {
+            return Ok(());
+        }
+        member.pending = false;
+        let online = member.online != 0;
+        self.send_join_requests();
+        if started {
+            self.send_member(
+                sckid,
+                ServerCommand::AnswersChanged {
+                    answers: Vec::new(),
+                },
+            );
+            self.send_member(sckid, ServerCommand::Started { remaining });
+        }
+        if online {
+            let member = self.member_mut(sckid)?;
+            let updated = ServerCommand::MemberUpdated {
+                member: (&*member).into(),
+            };
+            let changed = ServerCommand::MembersChanged {
+                members: self.get_group_members(),
+            };
+            self.send_all(updated);
+            self.send_all(changed);
+        }
+        Ok(())
+    }
 }
 
 impl From<&Member> for command::Member {
@@ -169,33 +767,47 @@ impl From<&Member> for command::Member {
         Self {
             sckid: value.sckid,
             name: value.name.clone(),
-            group: value.group.clone(),
+            group: value.group,
             x: value.x,
             y: value.y,
             answers: value.answers.len() as u32,
+            presence: if value.online != 0 {
+                Presence::Online
+            } else if value.away_since.is_some() {
+                Presence::Away
+            } else {
+                Presence::Offline
+            },
         }
     }
 }
 
 impl Member {
-    fn new(index: usize) -> Self {
+    fn new(index: usize, device: &str) -> Self {
         let mut rng = rand::thread_rng();
         let x = rng.gen_range(0.0..=100.0);
         let y = rng.gen_range(0.0..=100.0);
         Self {
             sckid: index as u32 + 1,
+            device: device.to_owned(),
             online: 0,
             name: format!("Aluno #{}", index + 1),
             group: x > 50.0,
             conns: Connections::new(),
             answers: BTreeMap::new(),
             kicked: false,
+            banned: false,
+            kicked_at: String::new(),
+            note: String::new(),
+            pending: false,
+            away_since: None,
             x,
             y,
         }
     }
-    fn send(&mut self, message: &Message) {
-        self.conns.send(message);
+    /// online, not kicked and not waiting for approval
+    fn is_visible(&self) -> bool {
+        (self.online != 0 || self.away_since.is_some()) && !self.kicked && !self.pending
     }
 }
 
@@ -205,55 +817,102 @@ impl Connections {
             senders: Vec::new(),
         }
     }
-    fn push(&mut self, sender: Sender<Message>) {
-        self.senders.push(Arc::new((sender, AtomicBool::new(true))));
-    }
-    fn send(&mut self, message: &Message) {
-        let mut index = 0;
-        while index < self.senders.len() {
-            let sender = &self.senders[index];
-            if sender.1.load(Ordering::Relaxed) {
-                let sender = Arc::clone(sender);
-                index += 1;
-                let message = message.clone();
-                tokio::spawn(async move {
-                    if sender.0.send(message).await.is_err() {
-                        sender.1.store(false, Ordering::Relaxed);
-                    }
-                });
-            } else {
-                self.senders.swap_remove(index);
-            }
-        }
+    fn push(&mut self, sender: EventSender) {
+        self.senders.push(sender);
+    }
+    /// a connection whose queue is full is dropped instead of waited for, dropping the
+    /// sender closes its websocket once the queue drains, and the client resyncs on reconnect
+    fn send(&mut self, event: &Arc<Event>) {
+        self.senders
+            .retain(|sender| match sender.try_send(Arc::clone(event)) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    metrics::SLOW_DISCONNECTS.inc();
+                    tracing::warn!("slow connection disconnected");
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            });
+    }
+    /// how many events are waiting in the queue of each connection
+    fn queued(&self) -> impl Iterator<Item = usize> + '_ {
+        self.senders
+            .iter()
+            .map(|sender| sender.max_capacity() - sender.capacity())
     }
     fn close(&mut self) {
         self.senders.clear()
     }
 }
 
-pub fn create_room() -> Result<String, ()> {
+pub fn create_room() -> Result<String, Error> {
     let mut rooms = STATE.rooms.borrow_mut();
     for _ in 0..30 {
         let code = random_room_code();
         if !rooms.contains_key(&code) {
-            rooms.insert(code.clone(), Room::new());
+            rooms.insert(code.clone(), Room::new(code.clone()));
+            tracing::info!(room = code, "room created");
+            webhook::send(WebhookEvent::RoomCreated { room: code.clone() });
             return Ok(code);
         }
     }
-    Err(())
+    Err(Error::NoFreeRoomCode)
 }
 
-pub fn join_room(room: &str) -> Result<u32, ()> {
+pub fn join_room(room: &str, device: &str) -> Result<u32, Error> {
     let mut rooms = STATE.rooms.borrow_mut();
-    let room = rooms.get_mut(room).ok_or(())?.interacted();
-    if room.game.is_started() {
-        return Err(());
+    let room = rooms.get_mut(room).ok_or(Error::RoomNotFound)?.interacted();
+    if room.banned.contains(device) {
+        return Err(Error::Banned);
     }
+    // the same browser joining again gets its old seat back
+    if let Some(member) = room
+        .members
+        .iter()
+        .find(|x| x.device == device && !x.kicked)
+    {
+        return Ok(member.sckid);
+    }
+    let pending = match (room.game.is_started(), room.late_join) {
+        (false, _) | (true, LateJoin::Allow) => false,
+        (true, LateJoin::Approval) => true,
+        (true, LateJoin::Deny) => return Err(Error::GameRunning),
+    };
     let index = room.members.len();
-    room.members.push(Member::new(index));
+    let mut member = Member::new(index, device);
+    member.pending = pending;
+    if pending {
+        room.send_master(ServerCommand::JoinRequest {
+            member: (&member).into(),
+        });
+    }
+    webhook::send(WebhookEvent::MemberJoined {
+        room: room.code.clone(),
+        member: (&member).into(),
+    });
+    room.members.push(member);
+    if pending {
+        room.send_join_requests();
+    }
     Ok(index as u32 + 1)
 }
 
+fn check_connect_to(room: &Room, sckid: u32) -> Result<(), Error> {
+    if sckid as usize > room.members.len() {
+        return Err(Error::MemberNotFound);
+    }
+    if sckid != 0 && room.members[sckid as usize - 1].kicked {
+        return Err(Error::Kicked);
+    }
+    Ok(())
+}
+
+/// checks that `connect_room` would succeed, without connecting
+pub fn check_connect(room: &str, sckid: u32) -> Result<(), Error> {
+    let rooms = STATE.rooms.borrow();
+    check_connect_to(rooms.get(room).ok_or(Error::RoomNotFound)?, sckid)
+}
+
 pub fn check_exists(room: &str, sckid: u32) -> bool {
     let rooms = STATE.rooms.borrow();
     if let Some(room) = rooms.get(room) {
@@ -264,85 +923,46 @@ pub fn check_exists(room: &str, sckid: u32) -> bool {
     }
 }
 
-pub fn connect_room(room: &str, sckid: u32) -> Result<tokio::sync::mpsc::Receiver<Message>, ()> {
+/// returns a sender to the new connection, for replies only meant for it, and its receiver,
+/// a client that saw events up to `last_seq` only receives the ones it missed, the sender is
+/// weak so that the connection closes once the room drops it, after a kick or when it is slow
+pub fn connect_room(
+    room: &str,
+    sckid: u32,
+    last_seq: Option<u64>,
+) -> Result<(WeakEventSender, EventReceiver), Error> {
     let mut rooms = STATE.rooms.borrow_mut();
-    let room = rooms.get_mut(room).ok_or(())?.interacted();
-    if sckid as usize > room.members.len() {
-        return Err(());
-    }
-    if sckid != 0 && room.members[sckid as usize - 1].kicked {
-        return Err(());
-    }
-    let (sender, receiver) = tokio::sync::mpsc::channel(100);
-
-    let mut messages: Vec<Message> = Vec::with_capacity(5);
+    let room = rooms.get_mut(room).ok_or(Error::RoomNotFound)?.interacted();
+    check_connect_to(room, sckid)?;
+    let (sender, receiver) = tokio::sync::mpsc::channel(CONNECTION_QUEUE_SIZE);
 
-    messages.push(room.into_message().into());
-    messages.push(
-        ServerCommand::MembersChanged {
-            members: room.get_group_members(),
-        }
-        .into(),
-    );
-    for member in &room.members {
-        if member.online != 0 && !member.kicked {
-            messages.push(
-                ServerCommand::MemberUpdated {
-                    member: member.into(),
-                }
-                .into(),
-            );
-        }
-    }
+    let events = match last_seq.and_then(|last_seq| room.replay(sckid, last_seq)) {
+        Some(events) => events,
+        None => room
+            .snapshot(sckid)
+            .into_iter()
+            .map(|command| Event::new(Some(room.seq), command))
+            .collect(),
+    };
 
-    match &room.game {
-        Game::Idle => {}
-        Game::Started { start, extra } => {
-            let answers = if sckid == 0 {
-                room.get_answers()
-            } else {
-                let member = (&room.members[sckid as usize - 1]).into();
-                let answers = room.members[sckid as usize - 1]
-                    .answers
-                    .iter()
-                    .map(|(&question, &answer)| Answer { question, answer })
-                    .collect();
-                vec![crate::command::MemberAnswers { member, answers }]
-            };
-            messages.push(ServerCommand::AnswersChanged { answers }.into());
-            messages.push(
-                ServerCommand::Started {
-                    remaining: (Duration::from_secs((room.event_time + extra) as u64)
-                        - start.elapsed())
-                    .as_secs() as u32,
-                }
-                .into(),
-            );
-        }
-        Game::Ended(message) => messages.push(message.clone()),
+    // queued before the connection is added to the room, so nothing can overtake them
+    for event in events {
+        let _ = sender.try_send(event);
     }
 
-    tokio::spawn({
-        let sender = sender.clone();
-        async move {
-            for message in messages {
-                let _ = sender.send(message).await;
-            }
-        }
-    });
-
+    let reply = sender.downgrade();
     if sckid == 0 {
         room.conns.push(sender);
     } else {
         let member = &mut room.members[sckid as usize - 1];
         member.conns.push(sender);
     }
-    Ok(receiver)
+    Ok((reply, receiver))
 }
 
-pub fn increment_online(room: &str, sckid: u32) -> Result<(), ()> {
+pub fn increment_online(room: &str, sckid: u32) -> Result<(), Error> {
     let mut rooms = STATE.rooms.borrow_mut();
-    let room = rooms.get_mut(room).ok_or(())?.interacted();
+    let room = rooms.get_mut(room).ok_or(Error::RoomNotFound)?.interacted();
     if sckid == 0 {
         // connection count of sckid 0 is not tracked
         return Ok(());
@@ -351,26 +971,27 @@ pub fn increment_online(room: &str, sckid: u32) -> Result<(), ()> {
         let member = &mut room.members[sckid as usize - 1];
         if !member.kicked {
             member.online += 1;
-            if member.online == 1 {
+            member.away_since = None;
+            if member.online == 1 && !member.pending {
                 let updated = ServerCommand::MemberUpdated {
                     member: (&*member).into(),
                 };
                 let changed = ServerCommand::MembersChanged {
                     members: room.get_group_members(),
                 };
-                room.send_all(&updated.into());
-                room.send_all(&changed.into());
+                room.send_all(updated);
+                room.send_all(changed);
             }
         }
         Ok(())
     } else {
-        Err(())
+        Err(Error::MemberNotFound)
     }
 }
 
-pub fn decrement_online(room: &str, sckid: u32) -> Result<(), ()> {
+pub fn decrement_online(room: &str, sckid: u32) -> Result<(), Error> {
     let mut rooms = STATE.rooms.borrow_mut();
-    let room = rooms.get_mut(room).ok_or(())?.interacted();
+    let room = rooms.get_mut(room).ok_or(Error::RoomNotFound)?.interacted();
     if sckid == 0 {
         // connection count of sckid 0 is not tracked
         return Ok(());
@@ -381,58 +1002,201 @@ pub fn decrement_online(room: &str, sckid: u32) -> Result<(), ()> {
             if member.online > 0 {
                 member.online -= 1;
             }
-            if member.online == 0 {
-                let removed = ServerCommand::MemberRemoved { sckid };
-                let changed = ServerCommand::MembersChanged {
-                    members: room.get_group_members(),
+            if member.online == 0 && !member.pending {
+                // only removed after the grace period, in case it is just changing networks
+                member.away_since = Some(Instant::now());
+                let updated = ServerCommand::MemberUpdated {
+                    member: (&*member).into(),
                 };
-                room.send_all(&removed.into());
-                room.send_all(&changed.into());
+                room.send_all(updated);
             }
         }
         Ok(())
     } else {
-        Err(())
+        Err(Error::MemberNotFound)
     }
 }
 
-pub struct AnyError {
-    pub err: String,
+#[derive(Debug)]
+pub enum Error {
+    RoomNotFound,
+    NoFreeRoomCode,
+    MemberNotFound,
+    GameRunning,
+    GameNotRunning,
+    Banned,
+    Kicked,
+    WaitingForApproval,
+    NamesLocked,
+    NameEmpty,
+    NameTooLong {
+        max_length: usize,
+    },
+    NameBlocked,
+    /// `x` and `y` must be finite and between 0 and 100
+    InvalidPosition,
+    InvalidCommand(serde_json::Error),
+    InvalidBinaryCommand(rmp_serde::decode::Error),
+    /// the client did not start with a `Hello`, or its version is not supported
+    IncompatibleProtocol {
+        client_version: Option<u32>,
+    },
 }
 
-impl<T: std::fmt::Debug> From<T> for AnyError {
-    fn from(value: T) -> Self {
-        Self {
-            err: format!("{:?}", value),
+impl Error {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::RoomNotFound => ErrorCode::RoomNotFound,
+            Error::NoFreeRoomCode => ErrorCode::NoFreeRoomCode,
+            Error::MemberNotFound => ErrorCode::MemberNotFound,
+            Error::GameRunning => ErrorCode::GameRunning,
+            Error::GameNotRunning => ErrorCode::GameNotRunning,
+            Error::Banned => ErrorCode::Banned,
+            Error::Kicked => ErrorCode::Kicked,
+            Error::WaitingForApproval => ErrorCode::WaitingForApproval,
+            Error::NamesLocked => ErrorCode::NamesLocked,
+            Error::NameEmpty => ErrorCode::NameEmpty,
+            Error::NameTooLong { .. } => ErrorCode::NameTooLong,
+            Error::NameBlocked => ErrorCode::NameBlocked,
+            Error::InvalidPosition => ErrorCode::InvalidPosition,
+            Error::InvalidCommand(_) | Error::InvalidBinaryCommand(_) => ErrorCode::InvalidCommand,
+            Error::IncompatibleProtocol { .. } => ErrorCode::IncompatibleProtocol,
+        }
+    }
+
+    /// the reply sent to the connection whose command failed
+    pub fn to_command(&self, command: Option<String>) -> ServerCommand {
+        ServerCommand::Error {
+            code: self.code(),
+            message: self.to_string(),
+            command,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::RoomNotFound => write!(f, "Room does not exist"),
+            Error::NoFreeRoomCode => {
+                write!(f, "Failed to create the room, presumably, it must be full")
+            }
+            Error::MemberNotFound => write!(f, "Member does not exist"),
+            Error::GameRunning => write!(f, "The game is running"),
+            Error::GameNotRunning => write!(f, "The game is not running"),
+            Error::Banned => write!(f, "You are banned from this room"),
+            Error::Kicked => write!(f, "Member was kicked from the room"),
+            Error::WaitingForApproval => write!(f, "Member is waiting for the master's approval"),
+            Error::NamesLocked => write!(f, "Names are locked by the master"),
+            Error::NameEmpty => write!(f, "Name is empty"),
+            Error::NameTooLong { max_length } => {
+                write!(f, "Name is longer than {max_length} characters")
+            }
+            Error::NameBlocked => write!(f, "Name contains a blocked word"),
+            Error::InvalidPosition => write!(f, "Position must be between 0 and 100"),
+            Error::InvalidCommand(error) => write!(f, "Invalid command: {error}"),
+            Error::InvalidBinaryCommand(error) => write!(f, "Invalid command: {error}"),
+            Error::IncompatibleProtocol {
+                client_version: None,
+            } => write!(f, "Expected a Hello as the first message"),
+            Error::IncompatibleProtocol {
+                client_version: Some(version),
+            } => write!(
+                f,
+                "Protocol version {version} is not supported, the server supports versions {} to {}",
+                command::MIN_PROTOCOL_VERSION,
+                command::PROTOCOL_VERSION
+            ),
         }
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Error::InvalidCommand(value)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(value: rmp_serde::decode::Error) -> Self {
+        Error::InvalidBinaryCommand(value)
+    }
+}
+
+/// parses a message from a client, text messages are json and binary ones are messagepack
+pub fn decode<T: serde::de::DeserializeOwned>(
+    encoding: Encoding,
+    message: &[u8],
+) -> Result<T, Error> {
+    match encoding {
+        Encoding::Json => Ok(serde_json::from_slice(message)?),
+        Encoding::MessagePack => Ok(rmp_serde::from_slice(message)?),
+    }
+}
+
 /// called every second
 pub fn periodic_routine(tick: usize) {
-    if tick % 1 == 0 {
+    {
+        let grace = Duration::from_secs(heartbeat().away_grace_secs);
         let mut rooms = STATE.rooms.borrow_mut();
         for room in rooms.values_mut() {
+            let mut removed = false;
+            for index in 0..room.members.len() {
+                let member = &mut room.members[index];
+                if member
+                    .away_since
+                    .is_some_and(|since| since.elapsed() >= grace)
+                {
+                    member.away_since = None;
+                    let sckid = member.sckid;
+                    room.send_all(ServerCommand::MemberRemoved { sckid });
+                    removed = true;
+                }
+            }
+            if removed {
+                let changed = ServerCommand::MembersChanged {
+                    members: room.get_group_members(),
+                };
+                room.send_all(changed);
+            }
+            if room.answers_dirty && tick.is_multiple_of(ANSWERS_SNAPSHOT_INTERVAL) {
+                room.answers_dirty = false;
+                room.send_master(ServerCommand::AnswersChanged {
+                    answers: room.get_answers(),
+                });
+            }
             if let Game::Started { start, extra } = room.game {
                 let elapsed = start.elapsed();
                 if elapsed > Duration::from_secs((room.event_time + extra) as u64) {
-                    let message = ServerCommand::Finished {
-                        member_answers: room.get_answers(),
-                        question_pool: room.question_pool.clone(),
-                    }
-                    .into();
-                    room.send_all(&message);
-                    room.game = Game::Ended(message);
-                    println!("[T] Game ended");
-                    dbg!(elapsed);
-                    dbg!(elapsed.as_secs());
-                    dbg!(room.event_time);
-                    dbg!(extra);
+                    room.finish();
                 }
             }
         }
     }
-    if tick % 3 == 0 {
+    {
+        let rooms = STATE.rooms.borrow();
+        let queued: Vec<usize> = rooms
+            .values()
+            .flat_map(|room| {
+                room.members
+                    .iter()
+                    .flat_map(|member| member.conns.queued())
+                    .chain(room.conns.queued())
+            })
+            .collect();
+        metrics::QUEUED_EVENTS.set(queued.iter().sum::<usize>() as i64);
+        metrics::MAX_QUEUE_DEPTH.set(queued.iter().max().copied().unwrap_or(0) as i64);
+        if tick.is_multiple_of(QUEUE_METRICS_INTERVAL) && !queued.is_empty() {
+            tracing::info!(
+                connections = queued.len(),
+                queued = queued.iter().sum::<usize>(),
+                longest = queued.iter().max().unwrap_or(&0),
+                slow_disconnects = metrics::SLOW_DISCONNECTS.get(),
+                "connection queues"
+            );
+        }
+    }
+    if tick.is_multiple_of(3) {
         let mut rooms = STATE.rooms.borrow_mut();
         while let Some(key) = rooms
             .iter_mut()
@@ -440,179 +1204,705 @@ pub fn periodic_routine(tick: usize) {
             .map(|(key, _)| key.clone())
             .next()
         {
+            tracing::info!(room = key, "room removed after an hour without interaction");
             if let Some(mut room) = rooms.remove(&key) {
-                room.send_all(&ServerCommand::RoomClosed.into());
+                room.send_all(ServerCommand::RoomClosed);
+                crate::lti::room_closed(&key);
+                webhook::send(WebhookEvent::RoomClosed { room: key });
             }
-            println!("[T] Room removed");
         }
     }
+    let (rooms, online) = room_counts();
+    metrics::ROOMS.set(rooms as i64);
+    metrics::MEMBERS_ONLINE.set(online as i64);
+}
+
+/// the settings and state of a room, for the REST api
+pub fn room_info(room: &str) -> Result<command::RoomInfo, Error> {
+    let rooms = STATE.rooms.borrow();
+    let room = rooms.get(room).ok_or(Error::RoomNotFound)?;
+    Ok(command::RoomInfo {
+        game: room.game.status(),
+        remaining: room.remaining(),
+        members: room.members.iter().filter(|x| x.is_visible()).count() as u32,
+        game_time: room.event_time,
+        question_pool: room.question_pool.clone(),
+        group_false_name: room.group_false_name.clone(),
+        group_false_color: room.group_false_color.clone(),
+        group_true_name: room.group_true_name.clone(),
+        group_true_color: room.group_true_color.clone(),
+        late_join: room.late_join,
+        names_locked: room.names_locked,
+    })
+}
+
+/// the members shown on the master's screen, for the REST api
+pub fn room_members(room: &str) -> Result<Vec<command::Member>, Error> {
+    let rooms = STATE.rooms.borrow();
+    Ok(rooms
+        .get(room)
+        .ok_or(Error::RoomNotFound)?
+        .get_group_members())
+}
+
+/// the answers of the members shown on the master's screen, for the REST api
+pub fn room_answers(room: &str) -> Result<Vec<command::MemberAnswers>, Error> {
+    let rooms = STATE.rooms.borrow();
+    Ok(rooms.get(room).ok_or(Error::RoomNotFound)?.get_answers())
+}
+
+/// every room of the server, for the admin console
+pub fn admin_rooms() -> Vec<command::AdminRoom> {
+    let rooms = STATE.rooms.borrow();
+    rooms
+        .values()
+        .map(|room| command::AdminRoom {
+            code: room.code.clone(),
+            game: room.game.status(),
+            remaining: room.remaining(),
+            question_pool: room.question_pool.clone(),
+            master_online: !room.conns.senders.is_empty(),
+            members: room.get_group_members(),
+            idle: room.last_interaction.elapsed().as_secs() as u32,
+        })
+        .collect()
+}
+
+/// the number of rooms and of members online
+pub fn room_counts() -> (u32, u32) {
+    let rooms = STATE.rooms.borrow();
+    let online = rooms
+        .values()
+        .flat_map(|room| &room.members)
+        .filter(|member| member.online > 0)
+        .count();
+    (rooms.len() as u32, online as u32)
+}
+
+/// sends an `Announcement` to every room, without counting as an interaction
+pub fn announce(message: String) {
+    let mut rooms = STATE.rooms.borrow_mut();
+    tracing::info!(
+        rooms = rooms.len(),
+        announcement = message,
+        "announcement sent"
+    );
+    for room in rooms.values_mut() {
+        room.send_all(ServerCommand::Announcement {
+            message: message.clone(),
+        });
+    }
+}
+
+/// handles master commands that do not come from a connection, like the ones of the REST
+/// api, they are broadcast to the room exactly like the ones from the master's websocket
+pub fn master_commands(
+    room_id: &str,
+    commands: impl IntoIterator<Item = command::MasterCommand>,
+) -> Result<(), Error> {
+    let mut rooms = STATE.rooms.borrow_mut();
+    rooms
+        .get_mut(room_id)
+        .ok_or(Error::RoomNotFound)?
+        .interacted();
+    for command in commands {
+        handle_master(&mut rooms, room_id, command)?;
+    }
+    Ok(())
 }
 
-pub fn handle_message(room_id: &str, sckid: u32, message: Message) -> Result<(), AnyError> {
+/// handles a member command that does not come from a connection, like the name
+/// brought by an LTI launch
+pub fn member_command(
+    room_id: &str,
+    sckid: u32,
+    command: command::MemberCommand,
+) -> Result<(), Error> {
     let mut rooms = STATE.rooms.borrow_mut();
     let room = rooms
         .get_mut(room_id)
-        .ok_or_else(|| format!("Room {} does not exist", room_id))?
+        .ok_or(Error::RoomNotFound)?
         .interacted();
     if sckid == 0 {
-        use crate::command::MasterCommand as Cmd;
-        match serde_json::from_slice(message.as_bytes())? {
-            Cmd::Start => {
-                room.clear_answers();
-                let member_updated = room
-                    .members
-                    .iter()
-                    .filter(|x| x.online != 0 && !x.kicked)
-                    .map(|x| ServerCommand::MemberUpdated { member: x.into() })
-                    .collect::<Vec<_>>();
-                for i in member_updated {
-                    room.send_master(&i.into());
+        return Err(Error::MemberNotFound);
+    }
+    check_connect_to(room, sckid)?;
+    handle_member(room, sckid, command)
+}
+
+/// sends every room a single `PositionsChanged` with the members that moved since the last call
+pub fn flush_positions() {
+    let mut rooms = STATE.rooms.borrow_mut();
+    for room in rooms.values_mut() {
+        if room.moved.is_empty() {
+            continue;
+        }
+        let moved = std::mem::take(&mut room.moved);
+        let positions: Vec<_> = moved
+            .into_iter()
+            .map(|sckid| &room.members[sckid as usize - 1])
+            .filter(|member| member.is_visible())
+            .map(|member| Position {
+                sckid: member.sckid,
+                x: member.x,
+                y: member.y,
+            })
+            .collect();
+        if !positions.is_empty() {
+            room.send_transient(ServerCommand::PositionsChanged { positions });
+        }
+    }
+}
+
+/// handles a command from sckid, returning the reply meant only for the connection that
+/// sent it, commands with an `id` are always acknowledged and never handled twice, unless
+/// they come from another `session`, or the `id` is reused for another command
+pub fn handle_message(
+    room_id: &str,
+    sckid: u32,
+    message: &[u8],
+    encoding: Encoding,
+) -> Result<Option<ServerCommand>, Error> {
+    let mut rooms = STATE.rooms.borrow_mut();
+    let Some(room) = rooms.get_mut(room_id) else {
+        return Err(count_error(Error::RoomNotFound));
+    };
+    let room = room.interacted();
+    // commands that could not be parsed did nothing, so their acks are not remembered
+    let (id, key, result) = if sckid == 0 {
+        match parse_request(encoding, message) {
+            Ok(Request::<command::MasterCommand> {
+                id,
+                session,
+                command,
+            }) => {
+                count_message(command.name());
+                let key = id.map(|id| AckKey::new(sckid, session, id, &command));
+                match key.as_ref().and_then(|key| room.acks.get(key)) {
+                    Some(ack) => return Ok(Some(ack)),
+                    None => (id, key, handle_master(&mut rooms, room_id, command)),
                 }
-                room.send_all(
-                    &ServerCommand::Started {
-                        remaining: room.event_time,
-                    }
-                    .into(),
-                );
-                room.game = Game::Started {
-                    start: Instant::now(),
-                    extra: 0,
-                };
             }
-            Cmd::Finish => {
-                if room.game.is_started() {
-                    let message = ServerCommand::Finished {
-                        member_answers: room.get_answers(),
-                        question_pool: room.question_pool.clone(),
-                    }
-                    .into();
-                    room.send_all(&message);
-                    room.game = Game::Ended(message);
-                }
+            Err((id, error)) => {
+                count_message("invalid");
+                (id, None, Err(error))
             }
-            Cmd::ExtraTime { seconds } => {
-                if let Game::Started { start: _, extra } = &mut room.game {
-                    *extra += seconds;
-                    room.send_all(&ServerCommand::ExtraTime { seconds }.into());
+        }
+    } else if sckid as usize - 1 < room.members.len() {
+        match parse_request(encoding, message) {
+            Ok(Request::<command::MemberCommand> {
+                id,
+                session,
+                command,
+            }) => {
+                count_message(command.name());
+                let key = id.map(|id| AckKey::new(sckid, session, id, &command));
+                match key.as_ref().and_then(|key| room.acks.get(key)) {
+                    Some(ack) => return Ok(Some(ack)),
+                    None => (id, key, handle_member(room, sckid, command)),
                 }
             }
-            Cmd::CloseRoom => {
-                room.send_all(&ServerCommand::RoomClosed.into());
-                rooms.remove(room_id);
+            Err((id, error)) => {
+                count_message("invalid");
+                (id, None, Err(error))
             }
-            Cmd::SetGroupName { group, name } => {
-                if group {
-                    room.group_true_name = name;
-                } else {
-                    room.group_false_name = name;
-                }
-                room.send_all(&room.into_message().into());
+        }
+    } else {
+        return Err(count_error(Error::MemberNotFound));
+    };
+    let result = result.map_err(count_error);
+    let Some(id) = id else {
+        return result.map(|()| None);
+    };
+    let ack = ServerCommand::Ack {
+        id,
+        ok: result.is_ok(),
+        error: result.as_ref().err().map(Error::code),
+        message: result.err().map(|error| error.to_string()),
+    };
+    // the room is gone after a CloseRoom
+    if let (Some(room), Some(key)) = (rooms.get_mut(room_id), key) {
+        room.acks.push(key, ack.clone());
+    }
+    Ok(Some(ack))
+}
+
+fn count_message(command: &str) {
+    metrics::MESSAGES_IN.with_label_values(&[command]).inc();
+}
+
+fn count_error(error: Error) -> Error {
+    let code = format!("{:?}", error.code());
+    metrics::ERRORS.with_label_values(&[&code]).inc();
+    error
+}
+
+/// the `id` of a command that could not be parsed is still acknowledged, if it can be found
+fn parse_request<C: serde::de::DeserializeOwned>(
+    encoding: Encoding,
+    message: &[u8],
+) -> Result<Request<C>, (Option<u64>, Error)> {
+    decode(encoding, message).map_err(|error| {
+        let id = decode::<Request<serde::de::IgnoredAny>>(encoding, message)
+            .ok()
+            .and_then(|request| request.id);
+        (id, error)
+    })
+}
+
+fn handle_master(
+    rooms: &mut BTreeMap<String, Room>,
+    room_id: &str,
+    command: command::MasterCommand,
+) -> Result<(), Error> {
+    let room = rooms.get_mut(room_id).ok_or(Error::RoomNotFound)?;
+    use crate::command::MasterCommand as Cmd;
+    match command {
+        Cmd::Start => {
+            room.clear_answers();
+            let member_updated = room
+                .members
+                .iter()
+                .filter(|x| x.is_visible())
+                .map(|x| ServerCommand::MemberUpdated { member: x.into() })
+                .collect::<Vec<_>>();
+            for i in member_updated {
+                room.send_master(i);
+            }
+            room.send_all(ServerCommand::Started {
+                remaining: room.event_time,
+            });
+            room.game = Game::Started {
+                start: Instant::now(),
+                extra: 0,
+            };
+            tracing::info!(room = room_id, game_time = room.event_time, "game started");
+            webhook::send(WebhookEvent::GameStarted {
+                room: room_id.to_owned(),
+                game_time: room.event_time,
+                question_pool: room.question_pool.clone(),
+                members: room.get_group_members(),
+            });
+        }
+        Cmd::Finish => {
+            if !room.game.is_started() {
+                return Err(Error::GameNotRunning);
+            }
+            room.finish();
+        }
+        Cmd::ExtraTime { seconds } => {
+            let Game::Started { start: _, extra } = &mut room.game else {
+                return Err(Error::GameNotRunning);
+            };
+            *extra += seconds;
+            room.send_all(ServerCommand::ExtraTime { seconds });
+        }
+        Cmd::CloseRoom => {
+            room.send_all(ServerCommand::RoomClosed);
+            rooms.remove(room_id);
+            tracing::info!(room = room_id, "room closed by the master");
+            crate::lti::room_closed(room_id);
+            webhook::send(WebhookEvent::RoomClosed {
+                room: room_id.to_owned(),
+            });
+        }
+        Cmd::SetGroupName { group, name } => {
+            if group {
+                room.group_true_name = name;
+            } else {
+                room.group_false_name = name;
             }
-            Cmd::SetGroupColor { group, color } => {
-                if group {
-                    room.group_true_color = color;
+            room.send_all(room.to_message());
+        }
+        Cmd::SetGroupColor { group, color } => {
+            if group {
+                room.group_true_color = color;
+            } else {
+                room.group_false_color = color;
+            }
+            room.send_all(room.to_message());
+        }
+        Cmd::SetTime { seconds } => {
+            if room.event_time != seconds {
+                room.event_time = seconds;
+                room.send_all(room.to_message());
+            }
+        }
+        Cmd::SetQuestionPool { question_pool } => {
+            if room.question_pool != question_pool {
+                room.question_pool = question_pool;
+                room.send_all(room.to_message());
+            }
+        }
+        Cmd::Kick { sckid } => room.kick(sckid, false)?,
+        Cmd::Ban { sckid } => room.kick(sckid, true)?,
+        Cmd::Unkick { sckid } => room.unkick(sckid)?,
+        Cmd::RenameMember { sckid, name } => {
+            let name = STATE.names.borrow().apply(room, sckid, &name)?;
+            let member = room.member_mut(sckid)?;
+            if member.name != name {
+                member.name = name;
+                if member.pending {
+                    room.send_join_requests();
                 } else {
-                    room.group_false_color = color;
+                    room.broadcast_member(sckid);
                 }
-                room.send_all(&room.into_message().into());
             }
-            Cmd::SetTime { seconds } => {
-                if room.event_time != seconds {
-                    room.event_time = seconds;
-                    room.send_all(&room.into_message().into());
+        }
+        Cmd::MoveMember { sckid, group } => {
+            let member = room.member_mut(sckid)?;
+            if member.group != group {
+                member.group = group;
+                // keep the avatar on the side of its group
+                if (member.x > 50.0) != group {
+                    member.x = 100.0 - member.x;
                 }
+                room.broadcast_member(sckid);
             }
-            Cmd::SetQuestionPool { question_pool } => {
-                if room.question_pool != question_pool {
-                    room.question_pool = question_pool;
-                    room.send_all(&room.into_message().into());
-                }
+        }
+        Cmd::SetMemberNote { sckid, note } => {
+            let member = room.member_mut(sckid)?;
+            if member.note != note {
+                member.note = note.clone();
+                room.send_master(ServerCommand::MemberNote { sckid, note });
             }
-            Cmd::Kick { sckid } => {
-                if sckid != 0 && sckid as usize - 1 < room.members.len() {
-                    let member = &mut room.members[sckid as usize - 1];
-                    member.send(&ServerCommand::RoomClosed.into());
-                    member.online = 0;
-                    member.answers.clear();
-                    member.name.clear();
-                    member.conns.close();
-                    member.kicked = true;
-                    room.send_all(&ServerCommand::MemberRemoved { sckid }.into());
-                    room.send_all(
-                        &ServerCommand::MembersChanged {
-                            members: room.get_group_members(),
-                        }
-                        .into(),
-                    );
-                }
+        }
+        Cmd::SetNamesLocked { locked } => {
+            if room.names_locked != locked {
+                room.names_locked = locked;
+                room.send_all(room.to_message());
             }
         }
-        Ok(())
-    } else if sckid as usize - 1 < room.members.len() {
-        let member = &mut room.members[sckid as usize - 1];
-        use crate::command::MemberCommand as Cmd;
-        match serde_json::from_slice(message.as_bytes())? {
-            Cmd::SetName { name } => {
-                if member.name != name {
-                    member.name = name;
-                    let message = ServerCommand::MemberUpdated {
-                        member: (&*member).into(),
+        Cmd::SetLateJoin { late_join } => {
+            if room.late_join != late_join {
+                room.late_join = late_join;
+                room.send_all(room.to_message());
+                if late_join == LateJoin::Allow {
+                    let pending = room
+                        .members
+                        .iter()
+                        .filter(|x| x.pending)
+                        .map(|x| x.sckid)
+                        .collect::<Vec<_>>();
+                    for sckid in pending {
+                        room.accept(sckid)?;
                     }
-                    .into();
-                    room.send_all(&message);
-                    room.send_all(
-                        &ServerCommand::MembersChanged {
-                            members: room.get_group_members(),
-                        }
-                        .into(),
-                    );
                 }
             }
-            Cmd::SetGroup { group } => {
-                if member.group != group {
-                    member.group = group;
-                    let message = ServerCommand::MemberUpdated {
-                        member: (&*member).into(),
-                    }
-                    .into();
-                    room.send_master(&message);
-                }
+        }
+        Cmd::AcceptJoin { sckid } => room.accept(sckid)?,
+        Cmd::RejectJoin { sckid } => {
+            if room.member_mut(sckid)?.pending {
+                room.kick(sckid, false)?;
             }
-            Cmd::SetPos { x, y } => {
-                member.x = x;
-                member.y = y;
+        }
+    }
+    Ok(())
+}
+
+fn handle_member(
+    room: &mut Room,
+    sckid: u32,
+    command: command::MemberCommand,
+) -> Result<(), Error> {
+    let names = STATE.names.borrow();
+    let member = &mut room.members[sckid as usize - 1];
+    // the name is the only thing a member waiting for approval may change, so that
+    // the master knows who is asking
+    if member.pending && !matches!(command, command::MemberCommand::SetName { .. }) {
+        return Err(Error::WaitingForApproval);
+    }
+    use crate::command::MemberCommand as Cmd;
+    match command {
+        Cmd::SetName { name } => {
+            if room.names_locked {
+                return Err(Error::NamesLocked);
+            }
+            let name = names.apply(room, sckid, &name)?;
+            let member = &mut room.members[sckid as usize - 1];
+            if member.name != name && member.pending {
+                member.name = name;
+                room.send_join_requests();
+            } else if member.name != name {
+                member.name = name;
                 let message = ServerCommand::MemberUpdated {
                     member: (&*member).into(),
-                }
-                .into();
-                room.send_all(&message);
-            }
-            Cmd::Answer { question, answer } => {
-                member.answers.insert(question, answer);
-                let member: command::Member = (&*member).into();
-                room.send_master(
-                    &ServerCommand::MemberUpdated {
-                        member: member.clone(),
-                    }
-                    .into(),
-                );
-                room.send_master(
-                    &ServerCommand::AnswerUpdated {
-                        answer: Answer { question, answer },
-                        member,
-                    }
-                    .into(),
-                );
-                room.send_master(
-                    &ServerCommand::AnswersChanged {
-                        answers: room.get_answers(),
-                    }
-                    .into(),
-                );
+                };
+                room.send_all(message);
+                room.send_all(ServerCommand::MembersChanged {
+                    members: room.get_group_members(),
+                });
             }
         }
-        Ok(())
-    } else {
-        Err(format!("Member {} of Room {} does not exist", sckid, room_id).into())
+        Cmd::SetGroup { group } => {
+            if member.group != group {
+                member.group = group;
+                let message = ServerCommand::MemberUpdated {
+                    member: (&*member).into(),
+                };
+                room.send_master(message);
+            }
+        }
+        Cmd::SetPos { x, y } => {
+            if !(0.0..=100.0).contains(&x) || !(0.0..=100.0).contains(&y) {
+                return Err(Error::InvalidPosition);
+            }
+            member.x = x;
+            member.y = y;
+            // sent in the next flush_positions, only the latest position of each member
+            room.moved.insert(sckid);
+        }
+        Cmd::Answer { question, answer } => {
+            if member.answers.insert(question, answer) == Some(answer) {
+                return Ok(());
+            }
+            // only the delta, the full answers follow in the periodic snapshot
+            let member = (&*member).into();
+            room.send_master(ServerCommand::AnswerUpdated {
+                answer: Answer { question, answer },
+                member,
+            });
+            room.answers_dirty = true;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room_with(names: &[&str]) -> Room {
+        let mut room = Room::new("BAB".to_owned());
+        for (index, name) in names.iter().enumerate() {
+            let mut member = Member::new(index, &format!("device{index}"));
+            member.name = (*name).to_owned();
+            room.members.push(member);
+        }
+        room
+    }
+
+    /// the rules, the names already in the room, the name asked for and the result
+    type NameCase<'a> = (
+        &'a NameRules,
+        &'a [&'a str],
+        &'a str,
+        Result<&'a str, ErrorCode>,
+    );
+
+    #[test]
+    fn name_rules_apply() {
+        let default = NameRules::default();
+        let short = NameRules {
+            max_length: 6,
+            ..NameRules::default()
+        };
+        let blocklist = NameRules {
+            blocklist: vec!["Bobo".to_owned()],
+            ..NameRules::default()
+        };
+        let raw = NameRules {
+            normalize: false,
+            disambiguate: false,
+            ..NameRules::default()
+        };
+        let tiny = NameRules {
+            max_length: MIN_DISAMBIGUATED_LENGTH,
+            ..NameRules::default()
+        };
+        let single = NameRules {
+            max_length: 1,
+            ..NameRules::default()
+        };
+        let digits = [
+            "Bia", "B 2", "B 3", "B 4", "B 5", "B 6", "B 7", "B 8", "B 9",
+        ];
+        let numbers = ["A", "2", "3", "4", "5", "6", "7", "8", "9"];
+        let cases: &[NameCase] = &[
+            (&default, &[], "Maria", Ok("Maria")),
+            (&default, &[], "  Maria \t  Silva ", Ok("Maria Silva")),
+            (&default, &[], "Ma\u{7}ria", Ok("Maria")),
+            (&default, &[], "   ", Err(ErrorCode::NameEmpty)),
+            (&default, &[], "\u{7}", Err(ErrorCode::NameEmpty)),
+            (&default, &[], "ａｎａ", Ok("ana")),
+            (&raw, &[], "ａｎａ", Ok("ａｎａ")),
+            (
+                &default,
+                &[],
+                "Maria Aparecida Silva",
+                Err(ErrorCode::NameTooLong),
+            ),
+            (&short, &[], "Renata", Ok("Renata")),
+            (&short, &[], "Renatas", Err(ErrorCode::NameTooLong)),
+            (
+                &blocklist,
+                &[],
+                "bobo da corte",
+                Err(ErrorCode::NameBlocked),
+            ),
+            (&blocklist, &[], "BOBO", Err(ErrorCode::NameBlocked)),
+            (&blocklist, &[], "Bobolina", Ok("Bobolina")),
+            (&default, &["Ana"], "ana", Ok("ana 2")),
+            (&default, &["Ana", "Ana 2"], "Ana", Ok("Ana 3")),
+            (&short, &["Renata"], "Renata", Ok("Rena 2")),
+            (&raw, &["Ana"], "Ana", Ok("Ana")),
+            (&tiny, &["Ana"], "Ana", Ok("A 2")),
+            (&tiny, &digits, "Bia", Ok("10")),
+            (&single, &["A"], "A", Ok("2")),
+            (&single, &numbers, "A", Ok("A")),
+        ];
+        for (rules, names, name, expected) in cases {
+            let room = room_with(names);
+            // a member that is not in `names`, so never compared to itself
+            let sckid = names.len() as u32 + 1;
+            let result = rules.apply(&room, sckid, name);
+            let result = result.as_deref().map_err(Error::code);
+            assert_eq!(result, *expected, "{name:?} with {names:?}");
+        }
+    }
+
+    #[test]
+    fn name_rules_apply_ignores_own_and_kicked_names() {
+        let rules = NameRules::default();
+        let mut room = room_with(&["Ana", "Bia"]);
+        assert_eq!(rules.apply(&room, 1, "Ana").unwrap(), "Ana");
+        room.members[1].kicked = true;
+        assert_eq!(rules.apply(&room, 1, "Bia").unwrap(), "Bia");
+    }
+
+    /// the `seq` and `cmd` of every event, `seconds` of `ExtraTime` tells them apart
+    fn summary(events: &[Arc<Event>]) -> Vec<(Option<u64>, String)> {
+        events
+            .iter()
+            .map(|event| match &event.command {
+                ServerCommand::ExtraTime { seconds } => (event.seq, format!("ExtraTime {seconds}")),
+                command => (event.seq, command.name().to_owned()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_from_last_seq() {
+        let mut room = room_with(&["Ana", "Bia"]);
+        room.send_all(ServerCommand::ExtraTime { seconds: 1 });
+        room.send_master(ServerCommand::ExtraTime { seconds: 2 });
+        room.send_member(1, ServerCommand::ExtraTime { seconds: 3 });
+        room.send_member(2, ServerCommand::ExtraTime { seconds: 4 });
+        room.send_all(ServerCommand::ExtraTime { seconds: 5 });
+
+        let positions = (None, "PositionsChanged".to_owned());
+        let extra = |seq, seconds| (Some(seq), format!("ExtraTime {seconds}"));
+        assert_eq!(
+            summary(&room.replay(0, 0).unwrap()),
+            [extra(1, 1), extra(2, 2), extra(5, 5), positions.clone()]
+        );
+        assert_eq!(
+            summary(&room.replay(1, 1).unwrap()),
+            [extra(3, 3), extra(5, 5), positions.clone()]
+        );
+        assert_eq!(
+            summary(&room.replay(2, 3).unwrap()),
+            [extra(4, 4), extra(5, 5), positions.clone()]
+        );
+        assert_eq!(summary(&room.replay(2, 5).unwrap()), [positions]);
+        // a seq the room never sent, the client is talking about another room
+        assert!(room.replay(2, 6).is_none());
+    }
+
+    #[test]
+    fn replay_sends_the_remaining_time() {
+        let mut room = room_with(&["Ana"]);
+        room.event_time = 300;
+        room.send_all(ServerCommand::Started {
+            remaining: room.event_time,
+        });
+        room.send_all(ServerCommand::ExtraTime { seconds: 10 });
+        room.game = Game::Started {
+            start: Instant::now() - Duration::from_secs(100),
+            extra: 10,
+        };
+        let events = room.replay(1, 0).unwrap();
+        assert_eq!(
+            summary(&events),
+            [
+                (Some(1), "Started".to_owned()),
+                (None, "PositionsChanged".to_owned())
+            ]
+        );
+        let ServerCommand::Started { remaining } = events[0].command else {
+            unreachable!();
+        };
+        assert!((209..=210).contains(&remaining), "{remaining}");
+        // an ExtraTime without the Started is added to the client's own timer
+        assert_eq!(
+            summary(&room.replay(1, 1).unwrap())[0],
+            (Some(2), "ExtraTime 10".to_owned())
+        );
+    }
+
+    #[test]
+    fn replay_too_old_falls_back_to_snapshot() {
+        let mut room = room_with(&[]);
+        assert!(room.replay(0, 0).is_some());
+        for seconds in 0..EVENT_LOG_SIZE as u32 + 10 {
+            room.send_all(ServerCommand::ExtraTime { seconds });
+        }
+        assert_eq!(room.log.len(), EVENT_LOG_SIZE);
+        let oldest = room.log.front().unwrap().1.seq.unwrap();
+        assert!(room.replay(0, oldest - 2).is_none());
+        assert!(room.replay(0, 0).is_none());
+        let events = room.replay(0, oldest - 1).unwrap();
+        assert_eq!(events.len(), EVENT_LOG_SIZE + 1);
+        assert_eq!(events[0].seq, Some(oldest));
+    }
+
+    #[test]
+    fn positions_are_not_logged() {
+        let mut room = room_with(&["Ana"]);
+        room.members[0].online = 1;
+        room.send_all(ServerCommand::ExtraTime { seconds: 1 });
+        for _ in 0..EVENT_LOG_SIZE * 2 {
+            room.send_transient(ServerCommand::PositionsChanged {
+                positions: Vec::new(),
+            });
+        }
+        assert_eq!(room.seq, 1);
+        assert_eq!(room.log.len(), 1);
+        let events = room.replay(0, 0).unwrap();
+        assert_eq!(events.len(), 2);
+        let ServerCommand::PositionsChanged { positions } = &events[1].command else {
+            panic!("expected the current positions after the replay");
+        };
+        assert_eq!(positions.len(), 1);
+        assert_eq!(positions[0].sckid, 1);
+    }
+
+    #[test]
+    fn acks_only_repeat_the_same_request() {
+        use command::MasterCommand;
+        let ack = |id| ServerCommand::Ack {
+            id,
+            ok: true,
+            error: None,
+            message: None,
+        };
+        let key = |session: &str, id, command: &MasterCommand| {
+            AckKey::new(0, Some(session.to_owned()), id, command)
+        };
+        let mut acks = Acks::new();
+        acks.push(key("a", 1, &MasterCommand::Start), ack(1));
+        assert!(acks.get(&key("a", 1, &MasterCommand::Start)).is_some());
+        // the page was reloaded, or another tab of the master
+        assert!(acks.get(&key("b", 1, &MasterCommand::Start)).is_none());
+        assert!(acks
+            .get(&AckKey::new(
+                1,
+                Some("a".to_owned()),
+                1,
+                &MasterCommand::Start
+            ))
+            .is_none());
+        assert!(acks.get(&key("a", 1, &MasterCommand::Finish)).is_none());
+        assert!(acks.get(&key("a", 2, &MasterCommand::Start)).is_none());
     }
 }
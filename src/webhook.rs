@@ -0,0 +1,363 @@
+//! Outgoing webhooks, configured by the `webhooks` field of the config file.
+//!
+//! Every event is POSTed as json to each url interested in it, signed with an
+//! HMAC-SHA256 of the body in the `X-Extensao-Signature` header, and retried
+//! with exponential backoff. The last deliveries are kept in memory and listed
+//! by `GET /api/webhooks`.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::command::{Member, MemberAnswers};
+
+/// how many times a delivery is attempted before giving up
+const MAX_ATTEMPTS: u32 = 5;
+/// delay before the first retry, doubled after every retry
+#[cfg(not(test))]
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// how many deliveries the delivery log remembers
+const DELIVERY_LOG_SIZE: usize = 256;
+
+static WEBHOOKS: OnceLock<Vec<Webhook>> = OnceLock::new();
+static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+static DELIVERIES: Mutex<VecDeque<Delivery>> = Mutex::new(VecDeque::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// key of the signature, the deliveries are not signed if it is empty
+    #[serde(default)]
+    pub secret: String,
+    /// names of the events sent to this url, all of them if it is empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+#[derive(Clone, serde::Serialize)]
+#[serde(tag = "event")]
+pub enum WebhookEvent {
+    RoomCreated {
+        room: String,
+    },
+    MemberJoined {
+        room: String,
+        member: Member,
+    },
+    GameStarted {
+        room: String,
+        game_time: u32,
+        question_pool: String,
+        members: Vec<Member>,
+    },
+    GameFinished {
+        room: String,
+        question_pool: String,
+        results: Vec<MemberAnswers>,
+    },
+    RoomClosed {
+        room: String,
+    },
+}
+
+/// the names of the events, for the `events` of each webhook
+pub const EVENTS: &[&str] = &[
+    "RoomCreated",
+    "MemberJoined",
+    "GameStarted",
+    "GameFinished",
+    "RoomClosed",
+];
+
+impl Webhook {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|x| x == event)
+    }
+}
+
+impl WebhookEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::RoomCreated { .. } => "RoomCreated",
+            WebhookEvent::MemberJoined { .. } => "MemberJoined",
+            WebhookEvent::GameStarted { .. } => "GameStarted",
+            WebhookEvent::GameFinished { .. } => "GameFinished",
+            WebhookEvent::RoomClosed { .. } => "RoomClosed",
+        }
+    }
+}
+
+/// the body of every delivery
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    /// the same for every url the event is sent to, and for every retry
+    id: u64,
+    /// rfc3339 timestamp of the event
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+/// an event sent to one url, as listed by `GET /api/webhooks`
+#[derive(Clone, serde::Serialize)]
+pub struct Delivery {
+    pub id: u64,
+    pub event: &'static str,
+    pub url: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// rfc3339 timestamp of the event
+    pub timestamp: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum DeliveryStatus {
+    /// not delivered yet, but will be retried
+    Pending,
+    Delivered,
+    /// gave up after `MAX_ATTEMPTS`
+    Failed,
+}
+
+pub fn set_webhooks(webhooks: Vec<Webhook>) {
+    let _ = WEBHOOKS.set(webhooks);
+}
+
+/// the last deliveries, oldest first
+pub fn deliveries() -> Vec<Delivery> {
+    DELIVERIES.lock().unwrap().iter().cloned().collect()
+}
+
+/// sends the event to every webhook interested in it, in the background
+pub fn send(event: WebhookEvent) {
+    let Some(webhooks) = WEBHOOKS.get() else {
+        return;
+    };
+    let name = event.name();
+    let webhooks = webhooks.iter().filter(|x| x.wants(name));
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let timestamp = chrono::Local::now().to_rfc3339();
+    let mut body = None;
+    for webhook in webhooks {
+        let body = body.get_or_insert_with(|| {
+            let payload = Payload {
+                id,
+                timestamp: timestamp.clone(),
+                event: &event,
+            };
+            serde_json::to_vec(&payload).expect("WebhookEvent is always serializable into json")
+        });
+        log(Delivery {
+            id,
+            event: name,
+            url: webhook.url.clone(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            timestamp: timestamp.clone(),
+        });
+        tokio::spawn(deliver(webhook.clone(), id, name, body.clone()));
+    }
+}
+
+async fn deliver(webhook: Webhook, id: u64, event: &'static str, body: Vec<u8>) {
+    let client = CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("failed to build the webhook http client")
+    });
+    let mut delay = FIRST_RETRY_DELAY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut request = client
+            .post(&webhook.url)
+            .header("content-type", "application/json")
+            .header("x-extensao-event", event)
+            .header("x-extensao-delivery", id.to_string())
+            .body(body.clone());
+        if !webhook.secret.is_empty() {
+            request = request.header("x-extensao-signature", signature(&webhook.secret, &body));
+        }
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                update(id, &webhook.url, attempt, DeliveryStatus::Delivered, None);
+                return;
+            }
+            Ok(response) => format!("HTTP {}", response.status()),
+            Err(error) => error.to_string(),
+        };
+        tracing::warn!(
+            event,
+            url = webhook.url,
+            attempt,
+            max_attempts = MAX_ATTEMPTS,
+            error,
+            "webhook delivery failed"
+        );
+        let status = if attempt == MAX_ATTEMPTS {
+            DeliveryStatus::Failed
+        } else {
+            DeliveryStatus::Pending
+        };
+        update(id, &webhook.url, attempt, status, Some(error));
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+}
+
+/// `sha256=` followed by the hex HMAC-SHA256 of the body
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+fn log(delivery: Delivery) {
+    let mut deliveries = DELIVERIES.lock().unwrap();
+    if deliveries.len() == DELIVERY_LOG_SIZE {
+        deliveries.pop_front();
+    }
+    deliveries.push_back(delivery);
+}
+
+fn update(id: u64, url: &str, attempts: u32, status: DeliveryStatus, error: Option<String>) {
+    let mut deliveries = DELIVERIES.lock().unwrap();
+    // it may have left the log already, if many events happened since
+    if let Some(delivery) = deliveries.iter_mut().find(|x| x.id == id && x.url == url) {
+        delivery.attempts = attempts;
+        delivery.status = status;
+        delivery.last_error = error;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{atomic::AtomicU32, Arc};
+    use warp::Filter;
+
+    #[test]
+    fn signature_is_hmac_sha256() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn events_are_filtered() {
+        let webhook = |events: &[&str]| Webhook {
+            url: String::new(),
+            secret: String::new(),
+            events: events.iter().map(|x| (*x).to_owned()).collect(),
+        };
+        assert!(EVENTS.iter().all(|event| webhook(&[]).wants(event)));
+        let some = webhook(&["RoomCreated", "GameFinished"]);
+        assert!(some.wants("RoomCreated"));
+        assert!(some.wants("GameFinished"));
+        assert!(!some.wants("GameStarted"));
+        assert!(!some.wants("roomcreated"));
+        let names = [
+            WebhookEvent::RoomCreated {
+                room: String::new(),
+            },
+            WebhookEvent::RoomClosed {
+                room: String::new(),
+            },
+        ]
+        .map(|event| event.name());
+        assert_eq!(names, ["RoomCreated", "RoomClosed"]);
+    }
+
+    /// a webhook receiver on a random local port, that counts the requests and
+    /// replies with `status`, or with 400 if the event or the signature are wrong
+    fn receiver(status: u16) -> (String, Arc<AtomicU32>) {
+        let requests = Arc::new(AtomicU32::new(0));
+        let counter = Arc::clone(&requests);
+        let route = warp::post()
+            .and(warp::header::<String>("x-extensao-event"))
+            .and(warp::header::optional::<String>("x-extensao-signature"))
+            .and(warp::body::bytes())
+            .map(
+                move |event: String, sent: Option<String>, body: warp::hyper::body::Bytes| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    let expected = signature("secret", &body);
+                    if event != "RoomCreated" || sent != Some(expected) {
+                        return warp::http::StatusCode::BAD_REQUEST;
+                    }
+                    warp::http::StatusCode::from_u16(status).unwrap()
+                },
+            );
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{address}/"), requests)
+    }
+
+    async fn deliver_to(url: String) -> Delivery {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        log(Delivery {
+            id,
+            event: "RoomCreated",
+            url: url.clone(),
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            last_error: None,
+            timestamp: String::new(),
+        });
+        let webhook = Webhook {
+            url: url.clone(),
+            secret: "secret".to_owned(),
+            events: Vec::new(),
+        };
+        deliver(webhook, id, "RoomCreated", br#"{"room":"BAB"}"#.to_vec()).await;
+        deliveries()
+            .into_iter()
+            .find(|x| x.id == id && x.url == url)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn deliver_succeeds() {
+        let (url, requests) = receiver(200);
+        let delivery = deliver_to(url).await;
+        assert!(delivery.status == DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_error, None);
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn deliver_retries_then_fails() {
+        let (url, requests) = receiver(500);
+        let delivery = deliver_to(url).await;
+        assert!(delivery.status == DeliveryStatus::Failed);
+        assert_eq!(delivery.attempts, MAX_ATTEMPTS);
+        assert_eq!(
+            delivery.last_error.as_deref(),
+            Some("HTTP 500 Internal Server Error")
+        );
+        assert_eq!(requests.load(Ordering::Relaxed), MAX_ATTEMPTS);
+    }
+}
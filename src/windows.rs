@@ -20,12 +20,14 @@ r"comandos válidos são:
   start     - inicia o serviço
   stop      - para o serviço
   status    - mostra o status do serviço
-  run       - executar imediatamente, sem ser um serviço";
+  run       - executar imediatamente, sem ser um serviço, aceita as opções abaixo
+  check-config - verifica o arquivo de configuração, aceita as opções abaixo
+o serviço só usa as variáveis de ambiente EXTENSAO_*";
 pub fn main() -> Result<(), windows_service::Error> {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
         if let Err(_) = service_dispatcher::start(SERVICE_NAME, ffi_service_main) {
-            crate::server::serve(None);
+            run(&[]);
         }
         return Ok(());
     }
@@ -35,13 +37,37 @@ pub fn main() -> Result<(), windows_service::Error> {
         "start" => start_service(),
         "stop" => stop_service(),
         "s" | "status" => status_service(),
-        "r" | "run" => crate::server::serve(None),
-        "h" | "help" => println!("{}", HELP_MESSAGE),
-        _ => println!("comando desconhecido: {}\n{}", args[1], HELP_MESSAGE),
+        "r" | "run" => run(&args[2..]),
+        "check-config" => match crate::cli::Options::parse(args[2..].iter().cloned()) {
+            Ok(options) => {
+                if !crate::config::check(&options) {
+                    std::process::exit(1);
+                }
+            }
+            Err(error) => println!("{}\n{}", error, crate::cli::HELP),
+        },
+        "h" | "help" => println!("{}\n{}", HELP_MESSAGE, crate::cli::HELP),
+        _ => println!(
+            "comando desconhecido: {}\n{}\n{}",
+            args[1],
+            HELP_MESSAGE,
+            crate::cli::HELP
+        ),
     }
     Ok(())
 }
 
+fn run(args: &[String]) {
+    match crate::cli::Options::parse(args.iter().cloned()) {
+        Ok(options) => {
+            if !crate::server::serve(None, options) {
+                std::process::exit(1);
+            }
+        }
+        Err(error) => println!("{}\n{}", error, crate::cli::HELP),
+    }
+}
+
 define_windows_service!(ffi_service_main, rust_service_main);
 
 fn rust_service_main(arguments: Vec<OsString>) {
@@ -81,13 +107,24 @@ fn run_service(_arguments: Vec<OsString>) -> windows_service::Result<()> {
         process_id: None,
     })?;
 
-    crate::server::serve(Some(shutdown_receiver));
+    // a service has no command line, only the environment variables
+    let started = match crate::cli::Options::parse(std::iter::empty()) {
+        Ok(options) => crate::server::serve(Some(shutdown_receiver), options),
+        Err(error) => {
+            println!("{}", error);
+            false
+        }
+    };
 
     status_handle.set_service_status(ServiceStatus {
         service_type: ServiceType::OWN_PROCESS,
         current_state: ServiceState::Stopped,
         controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
+        exit_code: if started {
+            ServiceExitCode::Win32(0)
+        } else {
+            ServiceExitCode::ServiceSpecific(1)
+        },
         checkpoint: 0,
         wait_hint: Duration::default(),
         process_id: None,
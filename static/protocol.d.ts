@@ -0,0 +1,83 @@
+// generated from src/command.rs by `extensao --typescript`, do not edit
+export const PROTOCOL_VERSION = 1;
+
+export type ClientHello = { "cmd": "Hello", protocol_version: number, capabilities: Array<string>, 
+/**
+ * `seq` of the last event seen, with the `resync` capability only the
+ * events missed since then are sent, instead of the whole room
+ */
+last_seq?: number, };
+
+export type Request<C> = { id?: number, 
+/**
+ * random for every page load, so that a reloaded page, which starts its `id`s
+ * again, or another tab of the master is never answered with their `Ack`s
+ */
+session?: string, } & C;
+
+export type MasterCommand = { "cmd": "Start" } | { "cmd": "Finish" } | { "cmd": "ExtraTime", seconds: number, } | { "cmd": "CloseRoom" } | { "cmd": "SetGroupName", group: boolean, name: string, } | { "cmd": "SetGroupColor", group: boolean, color: string, } | { "cmd": "SetTime", seconds: number, } | { "cmd": "SetQuestionPool", question_pool: string, } | { "cmd": "Kick", sckid: number, } | { "cmd": "SetLateJoin", late_join: LateJoin, } | { "cmd": "AcceptJoin", sckid: number, } | { "cmd": "RejectJoin", sckid: number, } | { "cmd": "Ban", sckid: number, } | { "cmd": "Unkick", sckid: number, } | { "cmd": "RenameMember", sckid: number, name: string, } | { "cmd": "MoveMember", sckid: number, group: boolean, } | { "cmd": "SetMemberNote", sckid: number, note: string, } | { "cmd": "SetNamesLocked", locked: boolean, };
+
+export type MemberCommand = { "cmd": "SetName", name: string, } | { "cmd": "SetGroup", group: boolean, } | { "cmd": "SetPos", x: number, y: number, } | { "cmd": "Answer", question: number, answer: number, };
+
+export type ServerCommand = { "cmd": "Hello", protocol_version: number, capabilities: Array<string>, } | { "cmd": "Started", remaining: number, } | { "cmd": "Finished", member_answers: Array<MemberAnswers>, question_pool: string, } | { "cmd": "ExtraTime", seconds: number, } | { "cmd": "RoomChanged", game_time: number, question_pool: string, group_false_name: string, group_false_color: string, group_true_name: string, group_true_color: string, late_join: LateJoin, names_locked: boolean, 
+/**
+ * longest name accepted by `SetName`, from the config of the server
+ */
+max_name_length: number, } | { "cmd": "AnswersChanged", answers: Array<MemberAnswers>, } | { "cmd": "MembersChanged", members: Array<Member>, } | { "cmd": "AnswerUpdated", answer: Answer, member: Member, } | { "cmd": "MemberUpdated", member: Member, } | { "cmd": "PositionsChanged", positions: Array<Position>, } | { "cmd": "MemberRemoved", sckid: number, } | { "cmd": "JoinRequest", member: Member, } | { "cmd": "JoinRequestsChanged", members: Array<Member>, } | { "cmd": "Error", code: ErrorCode, message: string, 
+/**
+ * the `cmd` of the rejected command, if it could be parsed,
+ * named `command` because `cmd` is the tag of this enum
+ */
+command: string | null, } | { "cmd": "Ack", id: number, ok: boolean, error: ErrorCode | null, message: string | null, } | { "cmd": "MemberNote", sckid: number, note: string, } | { "cmd": "KickedChanged", kicked: Array<KickedMember>, } | { "cmd": "Announcement", message: string, } | { "cmd": "RoomClosed" };
+
+export type ErrorCode = "RoomNotFound" | "NoFreeRoomCode" | "MemberNotFound" | "GameRunning" | "GameNotRunning" | "Banned" | "Kicked" | "WaitingForApproval" | "NamesLocked" | "NameEmpty" | "NameTooLong" | "NameBlocked" | "InvalidPosition" | "InvalidCommand" | "IncompatibleProtocol";
+
+export type LateJoin = "Deny" | "Allow" | "Approval";
+
+export type Presence = "Online" | "Away" | "Offline";
+
+export type Member = { sckid: number, name: string, group: boolean, x: number, y: number, answers: number, presence: Presence, };
+
+export type Position = { sckid: number, x: number, y: number, };
+
+export type Answer = { question: number, answer: number, };
+
+export type KickedMember = { member: Member, banned: boolean, 
+/**
+ * rfc3339 timestamp of the kick
+ */
+kicked_at: string, };
+
+export type MemberAnswers = { member: Member, answers: Array<Answer>, };
+
+export type RoomInfo = { game: GameStatus, 
+/**
+ * seconds left in the game, zero unless it is `Started`
+ */
+remaining: number, members: number, game_time: number, question_pool: string, group_false_name: string, group_false_color: string, group_true_name: string, group_true_color: string, late_join: LateJoin, names_locked: boolean, };
+
+export type GameStatus = "Idle" | "Started" | "Ended";
+
+export type RoomUpdate = { game_time?: number, question_pool?: string, group_false_name?: string, group_false_color?: string, group_true_name?: string, group_true_color?: string, late_join?: LateJoin, names_locked?: boolean, };
+
+export type AdminRoom = { code: string, game: GameStatus, 
+/**
+ * seconds left in the game, zero unless it is `Started`
+ */
+remaining: number, question_pool: string, master_online: boolean, members: Array<Member>, 
+/**
+ * seconds since the last command, the room is removed after an hour
+ */
+idle: number, };
+
+export type ServerStatus = { version: string, 
+/**
+ * seconds since the server started
+ */
+uptime: number, rooms: number, members_online: number, };
+
+export type AnnouncementRequest = { message: string, };
+
+export type ClientMessage = ClientHello | Request<MasterCommand> | Request<MemberCommand>;
+
+export type ServerMessage = { seq?: number } & ServerCommand;
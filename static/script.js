@@ -3,6 +3,16 @@ const scheme = (window.location.protocol.startsWith("https") ? "wss" : "ws");
 
 const api_websocket = window.location.href.slice(0, window.location.href.lastIndexOf('/') + 1).replace(/^http/, "ws") + "sala";
 
+/** alternativa ao websocket, para redes que bloqueiam ele: server-sent events
+ * para receber e post para enviar */
+const api_events = window.location.href.slice(0, window.location.href.lastIndexOf('/') + 1) + "sala/eventos";
+const api_command = window.location.href.slice(0, window.location.href.lastIndexOf('/') + 1) + "sala/comando";
+
+/** versão do protocolo do websocket implementada por este cliente, veja src/command.rs */
+const PROTOCOL_VERSION = 1;
+/** funcionalidades opcionais do protocolo que este cliente pede ao servidor */
+const PROTOCOL_CAPABILITIES = ["resync"];
+
 /** faz um request post para o url especificado, e chama callback com o
  * resultado */
 function post(url, callback, error_callback) {
@@ -26,44 +36,112 @@ function post(url, callback, error_callback) {
 }
 
 /** cria uma nova conecção websocket, se for desconectado, reconecta
- * automaticamente */
+ * automaticamente, a primeira mensagem é sempre o Hello
+ * se o websocket nunca conseguir abrir, usa server-sent events no lugar
+ * comandos enviados com on_ack recebem um id, e são reenviados após uma
+ * reconexão até o Ack chegar, o servidor nunca executa o mesmo id duas vezes
+ * os ids recomeçam a cada carregamento da página, por isso vão junto com uma
+ * sessão aleatória, para não receber os Acks de antes de recarregar */
 function connection(callback) {
+    // seq da última mensagem recebida, ao reconectar só recebemos o que perdemos
+    let last_seq = null;
+    let next_id = 1;
+    const session = Math.random().toString(36).slice(2) + Date.now().toString(36);
+    // comandos esperando um Ack, por id
+    let pending = new Map();
+    // se o websocket já abriu alguma vez, se não, provavelmente está bloqueado
+    let opened = false;
+    let events = null;
     let ws = new WebSocket(api_websocket);
+    ws.onopen = onopen;
     ws.onerror = onerror;
     ws.onmessage = onmessage;
+    function onopen() {
+        opened = true;
+        let hello = { cmd: "Hello", protocol_version: PROTOCOL_VERSION, capabilities: PROTOCOL_CAPABILITIES, last_seq };
+        console.log(">>>", hello);
+        ws.send(JSON.stringify(hello));
+    }
     function onerror(error) {
         ws.close();
+        if (!opened) {
+            ws = null;
+            open_events();
+            return;
+        }
         setTimeout(function () {
             if (ws === null) return;
             ws = new WebSocket(api_websocket);
+            ws.onopen = onopen;
             ws.onerror = onerror;
             ws.onmessage = onmessage;
         }, 3000);
     }
+    function open_events() {
+        let query = `?protocol_version=${PROTOCOL_VERSION}&capabilities=${PROTOCOL_CAPABILITIES.join(",")}`;
+        if (last_seq !== null) query += `&last_seq=${last_seq}`;
+        // o navegador reconecta sozinho, mandando o último seq no Last-Event-ID
+        events = new EventSource(api_events + query);
+        events.onmessage = function (ev) {
+            receive(JSON.parse(ev.data));
+        };
+    }
+    function transmit(data) {
+        console.log(">>>", data);
+        if (events !== null) {
+            fetch(api_command, { method: "POST", body: JSON.stringify(data), credentials: "include" }).then(function (response) {
+                if (response.status !== 204) return response.json().then(receive);
+            }).catch(function (error) {
+                console.error(error);
+            });
+        } else if (ws !== null && ws.readyState === WebSocket.OPEN) {
+            ws.send(JSON.stringify(data));
+        }
+    }
     function onmessage(ev) {
         if (typeof ev.data === "object") {
             ev.data.text().then(function (text) {
-                let json = JSON.parse(text);
-                console.log("<<<", json);
-                if (typeof callback === "function") {
-                    callback(json);
-                }
+                receive(JSON.parse(text));
             }).catch(function (error) {
                 console.error(error);
             });
         } else {
-            let json = JSON.parse(ev.data);
-            console.log("<<<", json);
+            receive(JSON.parse(ev.data));
+        }
+    }
+    function receive(json) {
+        console.log("<<<", json);
+        if (typeof json.seq === "number") last_seq = json.seq;
+        if (json.cmd === "Hello") {
+            pending.forEach(function (request) {
+                transmit(request.data);
+            });
+        } else if (json.cmd === "Ack") {
+            let request = pending.get(json.id);
+            if (request === undefined) return;
+            pending.delete(json.id);
+            request.on_ack(json);
+            return;
+        }
+        if (typeof callback === "function") {
             callback(json);
         }
     }
     return {
-        send: function (data) {
-            console.log(">>>", data);
-            ws.send(JSON.stringify(data));
+        send: function (data, on_ack) {
+            if (typeof on_ack === "function") {
+                data = Object.assign({ id: next_id++, session }, data);
+                pending.set(data.id, { data, on_ack });
+            }
+            transmit(data);
         },
         close: function () {
+            if (events !== null) {
+                events.close();
+                return;
+            }
             ws.close();
+            ws.onopen = undefined;
             ws.onerror = undefined;
             ws.onmessage = undefined;
         }